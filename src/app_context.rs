@@ -0,0 +1,160 @@
+// app_context.rs
+//! Which application has the keyboard focus, so modes can be limited to some applications.
+//!
+//! The engine asks its `ContextProvider` when a key goes down and only activates
+//! modes whose `app_match` allows the focused application. The Windows backend
+//! provides the foreground window; elsewhere nothing is known and only modes
+//! without an `app_match`, or with an excluding one, activate.
+use crate::mode_config::{AppMatch, ModeConfig};
+use regex::Regex;
+
+/// The focused application.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppContext {
+    /// The executable's file name, e.g. `Code.exe`.
+    pub process_name: String,
+    pub window_class: String,
+    pub title: String,
+}
+
+pub trait ContextProvider: Send + Sync {
+    /// The focused application, if it can be told.
+    fn current(&self) -> Option<AppContext>;
+}
+
+/// For platforms that can't tell which application has the focus.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoContext;
+
+impl ContextProvider for NoContext {
+    fn current(&self) -> Option<AppContext> {
+        None
+    }
+}
+
+/// A provider whose application is set by hand, counting how often it is asked.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct FakeContext {
+    context: std::sync::Mutex<Option<AppContext>>,
+    pub queries: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl FakeContext {
+    pub fn set(&self, context: Option<AppContext>) {
+        *self.context.lock().unwrap() = context;
+    }
+}
+
+#[cfg(test)]
+impl ContextProvider for FakeContext {
+    fn current(&self) -> Option<AppContext> {
+        self.queries
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.context.lock().unwrap().clone()
+    }
+}
+
+/// A mode's `app_match`, ready to check.
+#[derive(Debug, Clone)]
+pub struct AppFilter {
+    process_name: Option<String>,
+    window_class: Option<String>,
+    title: Option<Regex>,
+    exclude: bool,
+}
+
+impl AppFilter {
+    pub fn new(app_match: &AppMatch) -> Result<Self, String> {
+        if app_match.process_name.is_none()
+            && app_match.window_class.is_none()
+            && app_match.title.is_none()
+        {
+            return Err("needs a process_name, window_class or title".to_string());
+        }
+        let title = match &app_match.title {
+            Some(title) => {
+                Some(Regex::new(title).map_err(|e| format!("title is not a valid regex: {}", e))?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            process_name: app_match.process_name.clone(),
+            window_class: app_match.window_class.clone(),
+            title,
+            exclude: app_match.exclude,
+        })
+    }
+
+    /// The filter for a mode's `app_match`. Invalid ones were already reported by the loader.
+    pub fn for_mode(config: &ModeConfig) -> Option<Self> {
+        config
+            .app_match
+            .as_ref()
+            .and_then(|app_match| Self::new(app_match).ok())
+    }
+
+    fn matches(&self, context: &AppContext) -> bool {
+        self.process_name
+            .as_ref()
+            .is_none_or(|name| name.eq_ignore_ascii_case(&context.process_name))
+            && self
+                .window_class
+                .as_ref()
+                .is_none_or(|class| *class == context.window_class)
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|title| title.is_match(&context.title))
+    }
+
+    /// Whether the mode may activate in `context`. An unknown application matches nothing.
+    pub fn allows(&self, context: Option<&AppContext>) -> bool {
+        context.is_some_and(|context| self.matches(context)) != self.exclude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(json: &str) -> AppFilter {
+        AppFilter::new(&serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    fn app(process_name: &str, title: &str) -> AppContext {
+        AppContext {
+            process_name: process_name.to_string(),
+            window_class: "Window".to_string(),
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn every_field_that_is_set_has_to_match() {
+        let ide = filter(r#"{"process_name": "code.exe", "title": "\\.rs\\b"}"#);
+        assert!(ide.allows(Some(&app("Code.exe", "main.rs - crate"))));
+        assert!(!ide.allows(Some(&app("Code.exe", "README.md - crate"))));
+        assert!(!ide.allows(Some(&app("WindowsTerminal.exe", "main.rs"))));
+        assert!(!ide.allows(None));
+    }
+
+    #[test]
+    fn exclude_allows_everything_else() {
+        let not_in_games = filter(r#"{"process_name": "game.exe", "exclude": true}"#);
+        assert!(!not_in_games.allows(Some(&app("game.exe", "Game"))));
+        assert!(not_in_games.allows(Some(&app("Code.exe", "main.rs"))));
+        assert!(not_in_games.allows(None));
+    }
+
+    #[test]
+    fn bad_filters_are_reported() {
+        let err = |json: &str| AppFilter::new(&serde_json::from_str(json).unwrap()).unwrap_err();
+        assert_eq!(
+            err(r#"{"exclude": true}"#),
+            "needs a process_name, window_class or title"
+        );
+        assert!(err(r#"{"title": "("}"#).starts_with("title is not a valid regex"));
+    }
+}
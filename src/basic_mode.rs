@@ -1,11 +1,11 @@
 // basic_mode.rs
+use crate::app_context::AppFilter;
 use crate::conversion::*;
-use crate::input_simulator::simulate_key_tap;
-use crate::key_and_modifiers::KeyAndModifiers;
+use crate::input_sink::{key_tap_with_auto_modifiers, InputSink};
 use crate::key_state::KeyState;
-use crate::mode::Mode;
+use crate::mapping::Mapping;
+use crate::mode::{Activation, Mode};
 use crate::mode_config::ModeConfig;
-use crate::utils::current_time_ms;
 use log::info;
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
@@ -13,13 +13,48 @@ use std::time::{Duration, Instant};
 #[derive(Debug, Clone)]
 pub struct BasicMode {
     pub config: ModeConfig,
-    pub key_mapping: HashMap<u32, KeyAndModifiers>,
+    pub key_mapping: HashMap<u32, Mapping>,
     pub activation_keys: Vec<u32>,
+    pub activation_chord: Vec<u32>,
+    pub activation: Activation,
+    /// Limits the mode to some applications.
+    app_filter: Option<AppFilter>,
+    /// When a tap locked the mode on.
+    locked_at: Option<Instant>,
     /// Tracks the virtual key that activated this mode.
     pub activated_by: Option<u32>,
     pub auto_modifiers: Vec<u32>,
     pub was_mode_used: bool,
-    pub was_repeat: bool,
+    /// The held mapped key that is (or will start) auto-repeating.
+    repeating: Option<Repeating>,
+    /// Held keys that already repeated; their release types nothing more.
+    repeated_keys: Vec<u32>,
+    /// The tap-dance key being tapped, if any.
+    dance: Option<Dance>,
+    /// Mapped keys that produced output, for the usage statistics.
+    used_mappings: Vec<u32>,
+}
+
+/// A tap-dance key being tapped, waiting to see whether more taps follow.
+#[derive(Debug, Clone)]
+struct Dance {
+    vk_code: u32,
+    taps: u32,
+    shift: bool,
+    held: bool,
+    /// When the key last went down or came back up.
+    changed_at: Instant,
+    /// The key was held through the tapping term and already typed its hold output.
+    fired: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Repeating {
+    vk_code: u32,
+    shift: bool,
+    next_at: Instant,
+    interval: Duration,
+    fired: bool,
 }
 
 impl BasicMode {
@@ -30,21 +65,28 @@ impl BasicMode {
             .map(|s| string_to_vk(s))
             .collect();
 
+        let activation_chord = config
+            .activation_chord
+            .iter()
+            .map(|s| string_to_vk(s))
+            .collect();
+
+        let activation = Activation {
+            style: config.activation_style,
+            exit_key: config.exit_key.as_deref().map(string_to_vk),
+            double_tap: config.get_double_tap(),
+            one_shot_timeout: config.get_one_shot_timeout(),
+            tap_hold: config.tap_hold,
+            balanced_min: config.get_balanced_min(),
+            require_idle: config.get_require_idle(),
+        };
+
         let key_mapping = config
             .key_mapping
             .iter()
             .map(|(src_key, entry)| {
-                (
-                    string_to_vk(src_key),
-                    KeyAndModifiers {
-                        key: string_to_vk(&entry.key),
-                        modifiers: entry
-                            .modifiers
-                            .iter()
-                            .map(|m| string_to_modifier(m))
-                            .collect(),
-                    },
-                )
+                let mapping = Mapping::new(entry, config.repeat).with_auto_pair(config.auto_pair);
+                (string_to_vk(src_key), mapping)
             })
             .collect();
         let auto_modifiers: Vec<u32> = config
@@ -53,14 +95,127 @@ impl BasicMode {
             .map(|s| string_to_modifier(s))
             .collect();
 
+        let app_filter = AppFilter::for_mode(&config);
+
         Self {
             config,
             key_mapping,
             activation_keys,
-            auto_modifiers: auto_modifiers,
+            activation_chord,
+            activation,
+            app_filter,
+            locked_at: None,
+            auto_modifiers,
             activated_by: None,
             was_mode_used: false,
-            was_repeat: false,
+            repeating: None,
+            repeated_keys: Vec::new(),
+            dance: None,
+            used_mappings: Vec::new(),
+        }
+    }
+
+    /// Emit the mapping for `vk_code`, or tap the key itself if it isn't mapped.
+    fn tap_mapping(&self, vk_code: u32, shift: bool, sink: &mut dyn InputSink) {
+        match self.key_mapping.get(&vk_code) {
+            Some(mapping) => {
+                info!(
+                    "BasicMode: determine that we need to remap key {:#X} to {:?}",
+                    vk_code, mapping.steps
+                );
+                mapping.emit(shift, self.get_auto_modifiers(), sink);
+            }
+            None => {
+                let modifiers: &[u32] = if shift { &[0x10] } else { &[] };
+                key_tap_with_auto_modifiers(sink, vk_code, modifiers, self.get_auto_modifiers())
+            }
+        }
+    }
+
+    /// Type the output of the dance in progress: a held key picks its hold output if
+    /// there is one, otherwise the dance types its output for the taps so far.
+    fn finish_dance(&mut self, sink: &mut dyn InputSink) {
+        let Some(dance) = self.dance.take() else {
+            return;
+        };
+        let Some(mapping) = self.key_mapping.get(&dance.vk_code) else {
+            return;
+        };
+        if dance.fired {
+            return;
+        }
+        let held = dance
+            .held
+            .then(|| mapping.dance_step(dance.taps, true))
+            .flatten();
+        if let Some(output) = held.or_else(|| mapping.dance_step(dance.taps, false)) {
+            info!(
+                "BasicMode: tap dance on {:#X} ends after {} taps",
+                dance.vk_code, dance.taps
+            );
+            output.emit(dance.shift, &self.auto_modifiers, sink);
+            self.used_mappings.push(dance.vk_code);
+        }
+    }
+
+    /// A tap-dance key went up. The dance ends right away if no output waits for more taps.
+    fn release_dance(&mut self, vk_code: u32, released_at: Instant, sink: &mut dyn InputSink) {
+        let max_taps = self.key_mapping.get(&vk_code).map_or(0, |m| m.max_taps());
+        let Some(dance) = self
+            .dance
+            .as_mut()
+            .filter(|dance| dance.vk_code == vk_code && dance.held)
+        else {
+            return;
+        };
+        if dance.fired {
+            self.dance = None;
+            return;
+        }
+        dance.held = false;
+        dance.changed_at = released_at;
+        if dance.taps >= max_taps {
+            self.finish_dance(sink);
+        }
+    }
+
+    /// End a dance once the tapping term passes without another tap,
+    /// or type its hold output once the key is held through it.
+    fn update_dance(&mut self, now: Instant, sink: &mut dyn InputSink) {
+        let tapping_term = self.config.get_tapping_term();
+        let Some(dance) = self.dance.as_mut() else {
+            return;
+        };
+        if dance.fired || now.duration_since(dance.changed_at) < tapping_term {
+            return;
+        }
+        if !dance.held {
+            self.finish_dance(sink);
+            return;
+        }
+        let hold = self
+            .key_mapping
+            .get(&dance.vk_code)
+            .and_then(|m| m.dance_step(dance.taps, true));
+        if let Some(output) = hold {
+            output.emit(dance.shift, &self.auto_modifiers, sink);
+            dance.fired = true;
+            self.used_mappings.push(dance.vk_code);
+        }
+    }
+
+    /// Whether this release ends a press that already auto-repeated.
+    fn release_repeat(&mut self, vk_code: u32) -> bool {
+        if let Some(pos) = self.repeated_keys.iter().position(|key| *key == vk_code) {
+            self.repeated_keys.remove(pos);
+            return true;
+        }
+        match self.repeating.take() {
+            Some(repeating) if repeating.vk_code == vk_code => repeating.fired,
+            other => {
+                self.repeating = other;
+                false
+            }
         }
     }
 }
@@ -75,24 +230,59 @@ impl Mode for BasicMode {
     fn get_auto_modifiers(&self) -> &Vec<u32> {
         &self.auto_modifiers
     }
-    fn was_repeat(&self) -> bool {
-        self.was_repeat
-    }
-    fn set_was_repeat(&mut self, was_repeat: bool) {
-        self.was_repeat = was_repeat;
-    }
-    fn handle_key_down_event<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool {
-        //println!("Mode used = {}", self.was_mode_used);
-        // if the key is in the mapping or in the activation keys, then  true
-        let mut handled = false;
+    fn handle_key_down_event(
+        &mut self,
+        key_state: &mut KeyState,
+        sink: &mut dyn InputSink,
+    ) -> bool {
         let vk_code = key_state.vk_code as u32;
-        if self.activation_keys.contains(&vk_code) {
-            handled = true;
+        let tapping_term = self.config.get_tapping_term();
+        match self.dance.as_mut() {
+            Some(dance)
+                if dance.vk_code == vk_code
+                    && !dance.held
+                    && key_state.tap_count > 1
+                    && key_state.time_pressed.duration_since(dance.changed_at) < tapping_term =>
+            {
+                dance.taps += 1;
+                dance.held = true;
+                dance.changed_at = key_state.time_pressed;
+                return true;
+            }
+            // Any other key press ends the dance.
+            _ => self.finish_dance(sink),
         }
-        if self.key_mapping.contains_key(&vk_code) {
-            handled = true;
+        if self
+            .key_mapping
+            .get(&vk_code)
+            .is_some_and(|m| m.is_tap_dance())
+        {
+            self.dance = Some(Dance {
+                vk_code,
+                taps: 1,
+                shift: key_state.was_shift_held_on_key_down,
+                held: true,
+                changed_at: key_state.time_pressed,
+                fired: false,
+            });
+            return true;
         }
-
+        if let Some(repeat) = self.key_mapping.get(&vk_code).and_then(|m| m.repeat) {
+            // The newest held key takes over repeating, like the system's typematic repeat.
+            if let Some(previous) = self.repeating.take() {
+                if previous.fired {
+                    self.repeated_keys.push(previous.vk_code);
+                }
+            }
+            self.repeating = Some(Repeating {
+                vk_code,
+                shift: key_state.was_shift_held_on_key_down,
+                next_at: key_state.time_pressed + repeat.get_delay(),
+                interval: repeat.get_interval(),
+                fired: false,
+            });
+        }
+        //println!("Mode used = {}", self.was_mode_used);
         // If this key is one of the activation keys and we haven't activated yet,
         // record it as the activator.
         // Otherwise, if the key is in our key mapping, process it.
@@ -106,69 +296,82 @@ impl Mode for BasicMode {
         //     // simulate the key tap
         //     simulate_key_tap(mapping.key, &mapping.modifiers, &self.get_auto_modifiers());
         // }
-        handled = true;
-
-        return handled;
+        // Every key is handled while a basic mode is active.
+        true
     }
-    fn handle_key_up_event<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool {
+    fn handle_key_up_event(&mut self, key_state: &mut KeyState, sink: &mut dyn InputSink) -> bool {
         self.set_was_mode_used(true);
         let vk_code = key_state.vk_code as u32;
-        let mut handled = false;
-        if self.activation_keys.contains(&vk_code) {
-            handled = true;
+        if self
+            .key_mapping
+            .get(&vk_code)
+            .is_some_and(|m| m.is_tap_dance())
+        {
+            self.release_dance(vk_code, key_state.time_released, sink);
+            return true;
         }
-        if self.key_mapping.contains_key(&vk_code) {
-            handled = true;
+        if self.release_repeat(vk_code) {
+            // The repeats were the output.
+            return true;
         }
-        if let Some(mapping) = self.key_mapping.get(&vk_code) {
-            info!(
-                "BasicMode: determine that we need to remap key {:#X} to {:#X} with modifiers {:?}",
-                key_state.vk_code, mapping.key, mapping.modifiers
-            );
-            // simulate ctrl+c if it's the c key
-            key_state.time_released = Instant::now();
-            key_state.held = false;
-            handled = true;
-
-            // simulate the key tap
-            // combine the modifiers with the auto modifiers
-            let modifiers = [
-                &mapping.modifiers.clone().into_iter().collect::<Vec<u32>>()[..],
-                //if key_state.was_shift_held_on_key_down {
-                if key_state.was_shift_held_on_key_down {
-                    &[0x10]
-                } else {
-                    &[]
-                }, //} else {
-                   //    &[]
-            ]
-            .concat();
-            // if the key was held for more than 500ms, do not simulate the key tap
-            if key_state.time_pressed.elapsed().as_millis() < Duration::from_millis(500).as_millis()
-            {
-                simulate_key_tap(mapping.key, &modifiers, &self.get_auto_modifiers());
+        // if the key was held past the hold cutoff, do not simulate the key tap
+        let held_for = key_state
+            .time_released
+            .saturating_duration_since(key_state.time_pressed);
+        if held_for < self.config.get_hold_cutoff() {
+            self.tap_mapping(vk_code, key_state.was_shift_held_on_key_down, sink);
+            if self.maps_key(vk_code) {
+                self.used_mappings.push(vk_code);
             }
-        } else {
-            // Calculate modifiers directly into a Vec
-            let mut modifiers = Vec::new();
-            if key_state.was_shift_held_on_key_down {
-                modifiers.push(0x10);
-            }
-
-            handled = true;
-            // key_state.held = false; // Let main.rs handle state update
-
-            // Pass a slice reference (&modifiers) to simulate_key_tap
-            if key_state.time_pressed.elapsed().as_millis() < Duration::from_millis(500).as_millis()
-            {
-                simulate_key_tap(vk_code, &modifiers, &self.get_auto_modifiers());
+        }
+        // Every key is handled while a basic mode is active.
+        true
+    }
+    fn update(&mut self, now: Instant, sink: &mut dyn InputSink) {
+        self.update_dance(now, sink);
+        let Some(repeating) = self.repeating.as_mut() else {
+            return;
+        };
+        if now < repeating.next_at {
+            return;
+        }
+        if !repeating.fired {
+            // A held key counts as one use, however often it repeats.
+            self.used_mappings.push(repeating.vk_code);
+        }
+        // One repeat per update; after a stall, carry on from now rather than bursting.
+        repeating.fired = true;
+        repeating.next_at += repeating.interval;
+        if repeating.next_at <= now {
+            repeating.next_at = now + repeating.interval;
+        }
+        let (vk_code, shift) = (repeating.vk_code, repeating.shift);
+        self.set_was_mode_used(true);
+        self.tap_mapping(vk_code, shift, sink);
+    }
+    fn take_repeated_keys(&mut self) -> Vec<u32> {
+        let mut keys = std::mem::take(&mut self.repeated_keys);
+        if let Some(repeating) = self.repeating.take() {
+            if repeating.fired {
+                keys.push(repeating.vk_code);
             }
         }
-        // check if key is in key_mapping
-        return handled;
+        keys
+    }
+    fn take_used_mappings(&mut self) -> Vec<String> {
+        let used = std::mem::take(&mut self.used_mappings);
+        used.into_iter()
+            .filter_map(|vk_code| {
+                self.config
+                    .key_mapping
+                    .keys()
+                    .find(|name| string_to_vk(name) == vk_code)
+                    .cloned()
+            })
+            .collect()
     }
-    fn update(&mut self) {
-        // BasicMode has no periodic update.
+    fn finish_pending(&mut self, sink: &mut dyn InputSink) {
+        self.finish_dance(sink);
     }
     fn get_name(&self) -> &str {
         self.config.get_name()
@@ -176,7 +379,28 @@ impl Mode for BasicMode {
     fn get_activation_keys(&self) -> &Vec<u32> {
         &self.activation_keys
     }
-    fn check_if_deactivates<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool {
+    fn get_activation_chord(&self) -> &[u32] {
+        &self.activation_chord
+    }
+    fn get_chord_window(&self) -> Duration {
+        self.config.get_chord_window()
+    }
+    fn get_activation(&self) -> &Activation {
+        &self.activation
+    }
+    fn get_locked_at(&self) -> Option<Instant> {
+        self.locked_at
+    }
+    fn set_locked_at(&mut self, locked_at: Option<Instant>) {
+        self.locked_at = locked_at;
+    }
+    fn maps_key(&self, vk_code: u32) -> bool {
+        self.key_mapping.contains_key(&vk_code)
+    }
+    fn get_tap_timeout(&self) -> Duration {
+        self.config.get_tap_timeout()
+    }
+    fn check_if_deactivates(&mut self, key_state: &mut KeyState) -> bool {
         // Only deactivate if the key released is the one that activated the mode.
         info!(
             "Checking if BasicMode ({}) deactivates",
@@ -217,4 +441,111 @@ impl Mode for BasicMode {
     fn get_activated_by(&self) -> Option<u32> {
         self.activated_by
     }
+    fn get_app_filter(&self) -> Option<&AppFilter> {
+        self.app_filter.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_sink::{Action, RecordingSink};
+    use crate::mode_config::ModesConfig;
+    use crate::mode_json;
+
+    fn default_mode(name: &str) -> BasicMode {
+        let modes_config: ModesConfig = serde_json::from_str(&mode_json::get_json_str()).unwrap();
+        let config = modes_config
+            .modes
+            .into_iter()
+            .find(|cfg| cfg.name == name)
+            .unwrap();
+        BasicMode::new(config)
+    }
+
+    fn tap(mode: &mut BasicMode, vk_code: u32, held_for: Duration) -> Vec<Action> {
+        let mut sink = RecordingSink::new();
+        let mut key_state = KeyState::new(vk_code as i32, Instant::now());
+        key_state.held = true;
+        mode.handle_key_down_event(&mut key_state, &mut sink);
+        key_state.time_released = key_state.time_pressed + held_for;
+        key_state.held = false;
+        mode.handle_key_up_event(&mut key_state, &mut sink);
+        sink.actions
+    }
+
+    #[test]
+    fn delimeter_mode_maps_d_to_shift_comma() {
+        let mut mode = default_mode("delimeter");
+        mode.set_activated_by('K' as u32);
+        assert_eq!(
+            tap(&mut mode, 'D' as u32, Duration::from_millis(50)),
+            vec![Action::KeyTap {
+                vk_code: string_to_vk(","),
+                modifiers: vec![0x10]
+            }]
+        );
+        assert!(mode.was_mode_used());
+    }
+
+    #[test]
+    fn long_hold_drops_the_mapped_output() {
+        let mut mode = default_mode("delimeter");
+        mode.set_activated_by('K' as u32);
+        assert!(tap(&mut mode, 'D' as u32, Duration::from_millis(600)).is_empty());
+    }
+
+    #[test]
+    fn hold_cutoff_comes_from_the_mode_config() {
+        let mut mode = default_mode("delimeter");
+        mode.config.hold_cutoff_ms = Some(1000);
+        mode.set_activated_by('K' as u32);
+        assert_eq!(
+            tap(&mut mode, 'D' as u32, Duration::from_millis(600)).len(),
+            1
+        );
+        assert!(tap(&mut mode, 'D' as u32, Duration::from_millis(1000)).is_empty());
+    }
+
+    #[test]
+    fn held_mapped_key_repeats_until_released() {
+        let mut mode = default_mode("vim arrow keys");
+        mode.set_activated_by('J' as u32);
+        let t0 = Instant::now();
+        let mut sink = RecordingSink::new();
+        let mut key_state = KeyState::new('K' as i32, t0);
+        key_state.held = true;
+        mode.handle_key_down_event(&mut key_state, &mut sink);
+        for ms in [100, 299, 300, 320, 350, 399, 400] {
+            mode.update(t0 + Duration::from_millis(ms), &mut sink);
+        }
+        let left = Action::KeyTap {
+            vk_code: 0x25,
+            modifiers: vec![],
+        };
+        assert_eq!(sink.actions, vec![left.clone(), left.clone(), left]);
+        key_state.held = false;
+        key_state.time_released = t0 + Duration::from_millis(410);
+        mode.handle_key_up_event(&mut key_state, &mut sink);
+        mode.update(t0 + Duration::from_millis(500), &mut sink);
+        assert_eq!(sink.actions.len(), 3);
+    }
+
+    #[test]
+    fn quick_release_of_a_repeating_key_taps_once() {
+        let mut mode = default_mode("vim arrow keys");
+        mode.set_activated_by('J' as u32);
+        assert_eq!(
+            tap(&mut mode, 'K' as u32, Duration::from_millis(100)),
+            vec![Action::KeyTap {
+                vk_code: 0x25,
+                modifiers: vec![]
+            }]
+        );
+        mode.update(
+            Instant::now() + Duration::from_secs(1),
+            &mut RecordingSink::new(),
+        );
+        assert!(mode.take_repeated_keys().is_empty());
+    }
 }
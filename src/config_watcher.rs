@@ -0,0 +1,146 @@
+// config_watcher.rs
+//! Hot reload of `modes.json` and `mouse_config.json`.
+//!
+//! A background thread polls the modification times of both files. When one
+//! changes, both are parsed again and handed to the engine, which swaps them
+//! in once no mode is active. If parsing fails the old modes stay in place.
+use crate::engine::Engine;
+use crate::mode::Mode;
+use crate::mode_config::ModesConfig;
+use crate::mode_loader;
+use crate::mouse_mode::{MouseConfig, MouseMode};
+use log::{error, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tracks the modification times of the watched files.
+pub struct ConfigWatcher {
+    paths: Vec<PathBuf>,
+    last_modified: Vec<Option<SystemTime>>,
+}
+
+impl ConfigWatcher {
+    /// Start watching, taking the files as they are now as already loaded.
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let last_modified = paths.iter().map(|path| modified(path)).collect();
+        Self {
+            paths,
+            last_modified,
+        }
+    }
+
+    /// Whether any watched file changed, appeared or disappeared since the last poll.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last) in self.paths.iter().zip(self.last_modified.iter_mut()) {
+            let current = modified(path);
+            if current != *last {
+                info!("{:?} changed", path);
+                *last = current;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Parse both config files into the modes the engine can activate.
+pub fn load_modes(
+    modes_path: &Path,
+    mouse_config_path: &Path,
+) -> Result<Vec<Box<dyn Mode + Send>>, String> {
+    let modes_config: ModesConfig = mode_loader::read_json(modes_path)?;
+    let mouse_config: MouseConfig = mode_loader::read_json(mouse_config_path)?;
+    mode_loader::build_available_modes(modes_config, MouseMode::from_config(mouse_config)).map_err(
+        |e| {
+            let config_dir = modes_path.parent().unwrap_or(modes_path);
+            format!("{}:\n{}", config_dir.display(), e)
+        },
+    )
+}
+
+/// Watch the config directory and reload the engine's modes whenever a config file changes.
+pub fn spawn(config_dir: &Path, engine: &'static Mutex<Engine>) {
+    let modes_path = config_dir.join("modes.json");
+    let mouse_config_path = config_dir.join("mouse_config.json");
+    let mut watcher = ConfigWatcher::new(vec![modes_path.clone(), mouse_config_path.clone()]);
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if !watcher.poll() {
+            continue;
+        }
+        match load_modes(&modes_path, &mouse_config_path) {
+            Ok(modes) => {
+                println!("Reloaded configuration from {:?}", modes_path.parent());
+                engine.lock().unwrap().reload_modes(modes);
+            }
+            Err(e) => error!("Keeping the current modes, reload failed: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mode_json;
+    use crate::mouse_config_json;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bushido_keys_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn poll_notices_changed_files() {
+        let dir = temp_dir("watch");
+        let path = dir.join("modes.json");
+        fs::write(&path, "{}").unwrap();
+        let mut watcher = ConfigWatcher::new(vec![path.clone(), dir.join("missing.json")]);
+        assert!(!watcher.poll());
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+        fs::write(&path, "{}").unwrap();
+        assert!(watcher.poll());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_modes_reports_the_broken_file() {
+        let dir = temp_dir("load");
+        let modes_path = dir.join("modes.json");
+        let mouse_config_path = dir.join("mouse_config.json");
+        fs::write(&modes_path, mode_json::get_json_str()).unwrap();
+        fs::write(&mouse_config_path, mouse_config_json::get_json_str()).unwrap();
+        let modes = load_modes(&modes_path, &mouse_config_path).unwrap();
+        assert!(modes.iter().any(|mode| mode.get_name() == "num_mode"));
+
+        fs::write(&modes_path, "{\"modes\": [").unwrap();
+        let err = load_modes(&modes_path, &mouse_config_path).err().unwrap();
+        assert!(err.contains("modes.json"), "{}", err);
+
+        // A bad mouse config is reported, not a panic in the watcher thread.
+        fs::write(&modes_path, mode_json::get_json_str()).unwrap();
+        let mouse_config =
+            mouse_config_json::get_json_str().replacen('{', r#"{"exit_key": "", "#, 1);
+        fs::write(&mouse_config_path, mouse_config).unwrap();
+        let err = load_modes(&modes_path, &mouse_config_path).err().unwrap();
+        assert!(
+            err.ends_with(r#"mouse_config.json, exit_key: unknown key """#),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+// control.rs
+//! A local control channel, so other programs can ask which modes are active, reload
+//! the configuration, suspend and resume remapping, or lock a mode on.
+//!
+//! Commands are JSON objects, one per line, e.g. `{"command": "activate", "mode":
+//! "num_mode"}`. Each is answered with one line of JSON holding the outcome and the
+//! state afterwards. On Linux the channel is the Unix domain socket `control.sock` in
+//! the config directory, on Windows the named pipe `\\.\pipe\bushido_keys`.
+//! Each client is served on its own thread; `bushido_keys_rs ctl` is a small client.
+use crate::config_watcher;
+use crate::engine::Engine;
+use crate::input_sink::Action;
+use crate::mode::Mode;
+use crate::mode_loader;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+#[cfg(windows)]
+use std::fs::{File, OpenOptions};
+#[cfg(windows)]
+use std::os::windows::ffi::OsStrExt;
+#[cfg(windows)]
+use std::os::windows::io::{FromRawHandle, RawHandle};
+#[cfg(windows)]
+use windows::core::{HRESULT, PCWSTR};
+#[cfg(windows)]
+use windows::Win32::Foundation::{CloseHandle, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED};
+#[cfg(windows)]
+use windows::Win32::Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX};
+#[cfg(windows)]
+use windows::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, WaitNamedPipeW, PIPE_READMODE_BYTE,
+    PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+
+pub const USAGE: &str = "usage: bushido_keys_rs ctl status|reload|suspend|resume|activate <mode>";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Only report the state.
+    Status,
+    /// Read `modes.json` and `mouse_config.json` again.
+    Reload,
+    /// Let every key through untouched until `resume`.
+    Suspend,
+    Resume,
+    /// Lock a mode on, as if its activation key had been tapped.
+    Activate {
+        mode: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The active modes, bottom first.
+    #[serde(default)]
+    pub active_modes: Vec<String>,
+    #[serde(default)]
+    pub suspended: bool,
+}
+
+type Reload = dyn Fn() -> Result<Vec<Box<dyn Mode + Send>>, String> + Send + Sync;
+type Output = dyn Fn(&[Action]) + Send + Sync;
+
+/// What the commands need besides the engine.
+pub struct Hooks {
+    /// Loads the modes from the config files, for `reload`.
+    pub reload: Box<Reload>,
+    /// Sends output through the backend, e.g. the key releases of `suspend`.
+    pub send: Box<Output>,
+}
+
+/// Carry out one command line.
+pub fn handle(engine: &Mutex<Engine>, line: &str, hooks: &Hooks) -> Response {
+    let result = serde_json::from_str::<Command>(line)
+        .map_err(|e| format!("bad command: {}", e))
+        .and_then(|command| match command {
+            Command::Status => Ok(()),
+            Command::Reload => {
+                (hooks.reload)().map(|modes| engine.lock().unwrap().reload_modes(modes))
+            }
+            Command::Suspend => {
+                let actions = engine.lock().unwrap().suspend();
+                (hooks.send)(&actions);
+                Ok(())
+            }
+            Command::Resume => {
+                engine.lock().unwrap().resume();
+                Ok(())
+            }
+            Command::Activate { mode } => engine.lock().unwrap().activate_mode(&mode),
+        });
+    let engine = engine.lock().unwrap();
+    Response {
+        ok: result.is_ok(),
+        error: result.err(),
+        active_modes: engine
+            .active_mode_names()
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        suspended: engine.is_suspended(),
+    }
+}
+
+/// Answer every command a client sends until it hangs up.
+fn serve(
+    reader: impl BufRead,
+    mut writer: impl Write,
+    engine: &Mutex<Engine>,
+    hooks: &Hooks,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle(engine, &line, hooks);
+        let json = serde_json::to_string(&response).map_err(io::Error::other)?;
+        writeln!(writer, "{}", json)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Where the control channel lives.
+fn address(config_dir: &Path) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(r"\\.\pipe\bushido_keys")
+    } else {
+        config_dir.join("control.sock")
+    }
+}
+
+/// Listen for commands in the background; `send` carries out output through the backend.
+#[cfg_attr(not(any(windows, feature = "linux-evdev")), allow(dead_code))]
+pub fn spawn(
+    config_dir: &Path,
+    engine: &'static Mutex<Engine>,
+    send: impl Fn(&[Action]) + Send + Sync + 'static,
+) {
+    let address = address(config_dir);
+    let modes_path = config_dir.join("modes.json");
+    let mouse_config_path = config_dir.join("mouse_config.json");
+    let hooks = Arc::new(Hooks {
+        reload: Box::new(move || config_watcher::load_modes(&modes_path, &mouse_config_path)),
+        send: Box::new(send),
+    });
+    thread::spawn(move || {
+        if let Err(e) = listen(&address, engine, hooks) {
+            error!("Control channel {:?} stopped: {}", address, e);
+        }
+    });
+}
+
+/// Serve a client on its own thread, so a slow one doesn't hold up the others.
+fn serve_in_background<S>(stream: S, engine: &'static Mutex<Engine>, hooks: &Arc<Hooks>)
+where
+    S: Send + 'static,
+    for<'a> &'a S: io::Read + Write,
+{
+    let hooks = Arc::clone(hooks);
+    thread::spawn(move || {
+        if let Err(e) = serve(BufReader::new(&stream), &stream, engine, &hooks) {
+            error!("Control client failed: {}", e);
+        }
+    });
+}
+
+#[cfg(unix)]
+fn listen(path: &Path, engine: &'static Mutex<Engine>, hooks: Arc<Hooks>) -> io::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another instance is listening",
+        ));
+    }
+    // Nothing answers, so the socket was left behind by an earlier run and would make
+    // the bind fail.
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for commands on {:?}", path);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => serve_in_background(stream, engine, &hooks),
+            Err(e) => error!("Failed to accept a control client: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(path: &Path, engine: &'static Mutex<Engine>, hooks: Arc<Hooks>) -> io::Result<()> {
+    let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    // The first instance fails if another process already owns the name.
+    let mut open_mode = PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE;
+    info!("Listening for commands on {:?}", path);
+    loop {
+        let pipe = unsafe {
+            CreateNamedPipeW(
+                PCWSTR(name.as_ptr()),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                4096,
+                4096,
+                0,
+                None,
+            )
+        };
+        if pipe.is_invalid() {
+            return Err(io::Error::last_os_error());
+        }
+        open_mode = PIPE_ACCESS_DUPLEX;
+        if let Err(e) = unsafe { ConnectNamedPipe(pipe, None) } {
+            // The client may connect between the two calls.
+            if e.code() != HRESULT::from_win32(ERROR_PIPE_CONNECTED.0) {
+                let _ = unsafe { CloseHandle(pipe) };
+                error!("Failed to accept a control client: {}", e);
+                continue;
+            }
+        }
+        // Dropping the file closes this instance of the pipe. The next instance is
+        // created right away, so other clients can connect meanwhile.
+        let pipe = unsafe { File::from_raw_handle(pipe.0 as RawHandle) };
+        serve_in_background(pipe, engine, &hooks);
+    }
+}
+
+#[cfg(unix)]
+fn connect(path: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(path)
+}
+
+#[cfg(windows)]
+fn connect(path: &Path) -> io::Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        // Every instance is taken; wait for the listener to create the next one.
+        Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {
+            let name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+            let _ = unsafe { WaitNamedPipeW(PCWSTR(name.as_ptr()), 2000) };
+            OpenOptions::new().read(true).write(true).open(path)
+        }
+        result => result,
+    }
+}
+
+fn parse_command(args: &[String]) -> Result<Command, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["status"] => Ok(Command::Status),
+        ["reload"] => Ok(Command::Reload),
+        ["suspend"] => Ok(Command::Suspend),
+        ["resume"] => Ok(Command::Resume),
+        ["activate", mode] => Ok(Command::Activate {
+            mode: mode.to_string(),
+        }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// Entry point for `bushido_keys_rs ctl ...`: send one command and print the answer.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let command = parse_command(args)?;
+    let address = address(&mode_loader::config_dir()?);
+    let not_running =
+        |e: io::Error| format!("{}: {} (is bushido keys running?)", address.display(), e);
+    let stream = connect(&address).map_err(not_running)?;
+    let request = serde_json::to_string(&command).map_err(|e| e.to_string())?;
+    writeln!(&stream, "{}", request).map_err(not_running)?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(not_running)?;
+    println!("{}", line.trim_end());
+    let response: Response =
+        serde_json::from_str(&line).map_err(|e| format!("bad response: {}", e))?;
+    match response.error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::modes_with;
+
+    fn default_modes() -> Result<Vec<Box<dyn Mode + Send>>, String> {
+        Ok(modes_with(|_| {}))
+    }
+
+    fn engine() -> Mutex<Engine> {
+        Mutex::new(Engine::new(default_modes().unwrap()))
+    }
+
+    fn hooks() -> Hooks {
+        Hooks {
+            reload: Box::new(default_modes),
+            send: Box::new(|_| {}),
+        }
+    }
+
+    #[test]
+    fn commands_report_the_state_they_leave() {
+        let engine = engine();
+        let hooks = hooks();
+        let run = |line: &str| handle(&engine, line, &hooks);
+        assert_eq!(
+            run(r#"{"command": "status"}"#),
+            Response {
+                ok: true,
+                ..Default::default()
+            }
+        );
+        let activated = run(r#"{"command": "activate", "mode": "num_mode"}"#);
+        assert!(activated.ok);
+        assert_eq!(activated.active_modes, vec!["num_mode"]);
+        let again = run(r#"{"command": "activate", "mode": "num_mode"}"#);
+        assert_eq!(
+            again.error.as_deref(),
+            Some(r#"mode "num_mode" is already active"#)
+        );
+        assert_eq!(
+            run(r#"{"command": "activate", "mode": "nope"}"#)
+                .error
+                .as_deref(),
+            Some(r#"no mode named "nope""#)
+        );
+
+        let suspended = run(r#"{"command": "suspend"}"#);
+        assert!(suspended.ok && suspended.suspended);
+        assert!(suspended.active_modes.is_empty());
+        assert!(!run(r#"{"command": "activate", "mode": "num_mode"}"#).ok);
+        assert!(!run(r#"{"command": "resume"}"#).suspended);
+        assert!(run(r#"{"command": "reload"}"#).ok);
+        assert!(run(r#"{"command": "dance"}"#)
+            .error
+            .is_some_and(|e| e.starts_with("bad command")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clients_talk_over_the_socket() {
+        let path =
+            std::env::temp_dir().join(format!("bushido_keys_control_{}.sock", std::process::id()));
+        let engine: &'static Mutex<Engine> = Box::leak(Box::new(engine()));
+        let socket = path.clone();
+        thread::spawn(move || listen(&socket, engine, Arc::new(hooks())));
+        let connect_when_up = || {
+            (0..100)
+                .find_map(|_| {
+                    thread::sleep(std::time::Duration::from_millis(10));
+                    connect(&path).ok()
+                })
+                .expect("the control socket never came up")
+        };
+        // A client that never finishes its line doesn't hold up the others.
+        let idle = connect_when_up();
+        write!(&idle, r#"{{"command": "#).unwrap();
+
+        let stream = connect_when_up();
+        writeln!(&stream, r#"{{"command": "activate", "mode": "num_mode"}}"#).unwrap();
+        writeln!(&stream, r#"{{"command": "status"}}"#).unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        for _ in 0..2 {
+            let response: Response = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            assert_eq!(response.active_modes, vec!["num_mode"]);
+        }
+
+        // The socket of a running instance is left alone.
+        let err = listen(&path, engine, Arc::new(hooks())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(connect(&path).is_ok());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn ctl_arguments_become_commands() {
+        let args = |args: &[&str]| {
+            parse_command(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            args(&["activate", "num_mode"]),
+            Ok(Command::Activate {
+                mode: "num_mode".to_string()
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&args(&["suspend"]).unwrap()).unwrap(),
+            r#"{"command":"suspend"}"#
+        );
+        assert_eq!(args(&["activate"]), Err(USAGE.to_string()));
+    }
+}
@@ -1,149 +1,179 @@
+/// Every key name accepted in the config, with its virtual key code.
+pub const KEY_NAMES: &[(&str, u32)] = &[
+    // Alphabet
+    ("A", 0x41),
+    ("B", 0x42),
+    ("C", 0x43),
+    ("D", 0x44),
+    ("E", 0x45),
+    ("F", 0x46),
+    ("G", 0x47),
+    ("H", 0x48),
+    ("I", 0x49),
+    ("J", 0x4A),
+    ("K", 0x4B),
+    ("L", 0x4C),
+    ("M", 0x4D),
+    ("N", 0x4E),
+    ("O", 0x4F),
+    ("P", 0x50),
+    ("Q", 0x51),
+    ("R", 0x52),
+    ("S", 0x53),
+    ("T", 0x54),
+    ("U", 0x55),
+    ("V", 0x56),
+    ("W", 0x57),
+    ("X", 0x58),
+    ("Y", 0x59),
+    ("Z", 0x5A),
+    // Numbers
+    ("0", 0x30),
+    ("1", 0x31),
+    ("2", 0x32),
+    ("3", 0x33),
+    ("4", 0x34),
+    ("5", 0x35),
+    ("6", 0x36),
+    ("7", 0x37),
+    ("8", 0x38),
+    ("9", 0x39),
+    // Function Keys
+    ("F1", 0x70),
+    ("F2", 0x71),
+    ("F3", 0x72),
+    ("F4", 0x73),
+    ("F5", 0x74),
+    ("F6", 0x75),
+    ("F7", 0x76),
+    ("F8", 0x77),
+    ("F9", 0x78),
+    ("F10", 0x79),
+    ("F11", 0x7A),
+    ("F12", 0x7B),
+    // Numpad Keys
+    ("NUMPAD0", 0x60),
+    ("NUMPAD1", 0x61),
+    ("NUMPAD2", 0x62),
+    ("NUMPAD3", 0x63),
+    ("NUMPAD4", 0x64),
+    ("NUMPAD5", 0x65),
+    ("NUMPAD6", 0x66),
+    ("NUMPAD7", 0x67),
+    ("NUMPAD8", 0x68),
+    ("NUMPAD9", 0x69),
+    ("MULTIPLY", 0x6A),
+    ("ADD", 0x6B),
+    ("SEPARATOR", 0x6C),
+    ("SUBTRACT", 0x6D),
+    ("DECIMAL", 0x6E),
+    ("DIVIDE", 0x6F),
+    // Special Characters
+    (";", 0xBA),  // VK_OEM_1
+    (":", 0xBA),  // Shift + VK_OEM_1
+    ("=", 0xBB),  // VK_OEM_PLUS
+    ("+", 0xBB),  // Shift + VK_OEM_PLUS
+    (",", 0xBC),  // VK_OEM_COMMA
+    ("<", 0xBC),  // Shift + VK_OEM_COMMA
+    ("-", 0xBD),  // VK_OEM_MINUS
+    ("_", 0xBD),  // Shift + VK_OEM_MINUS
+    (".", 0xBE),  // VK_OEM_PERIOD
+    (">", 0xBE),  // Shift + VK_OEM_PERIOD
+    ("/", 0xBF),  // VK_OEM_2
+    ("?", 0xBF),  // Shift + VK_OEM_2
+    ("`", 0xC0),  // VK_OEM_3
+    ("~", 0xC0),  // Shift + VK_OEM_3
+    ("[", 0xDB),  // VK_OEM_4
+    ("{", 0xDB),  // Shift + VK_OEM_4
+    ("\\", 0xDC), // VK_OEM_5
+    ("|", 0xDC),  // Shift + VK_OEM_5
+    ("]", 0xDD),  // VK_OEM_6
+    ("}", 0xDD),  // Shift + VK_OEM_6
+    ("'", 0xDE),  // VK_OEM_7
+    ("\"", 0xDE), // Shift + VK_OEM_7
+    // Control Keys
+    ("BACKSPACE", 0x08),
+    ("TAB", 0x09),
+    ("CLEAR", 0x0C),
+    ("ENTER", 0x0D),
+    ("SHIFT", 0x10),
+    ("CTRL", 0x11),
+    ("ALT", 0x12),
+    ("PAUSE", 0x13),
+    ("CAPSLOCK", 0x14),
+    ("ESC", 0x1B),
+    ("SPACEBAR", 0x20),
+    ("PAGEUP", 0x21),
+    ("PAGEDOWN", 0x22),
+    ("END", 0x23),
+    ("HOME", 0x24),
+    ("LEFT", 0x25),
+    ("UP", 0x26),
+    ("RIGHT", 0x27),
+    ("DOWN", 0x28),
+    ("SELECT", 0x29),
+    ("PRINT", 0x2A),
+    ("EXECUTE", 0x2B),
+    ("PRINTSCREEN", 0x2C),
+    ("INSERT", 0x2D),
+    ("DELETE", 0x2E),
+    ("HELP", 0x2F),
+    // Windows Keys
+    ("LWIN", 0x5B),
+    ("RWIN", 0x5C),
+    ("WINDOWS", 0x5B | 0x5C | 0x5D),
+    ("APPS", 0x5D),
+    // Numeric Keypad
+    ("NUMLOCK", 0x90),
+    ("SCROLLLOCK", 0x91),
+    // Media Keys
+    ("VOLUME_MUTE", 0xAD),
+    ("VOLUME_DOWN", 0xAE),
+    ("VOLUME_UP", 0xAF),
+];
 
-pub fn string_to_vk(s: &str) -> u32 {
-    match s {
-        // Alphabet
-        "A" => 0x41,
-        "B" => 0x42,
-        "C" => 0x43,
-        "D" => 0x44,
-        "E" => 0x45,
-        "F" => 0x46,
-        "G" => 0x47,
-        "H" => 0x48,
-        "I" => 0x49,
-        "J" => 0x4A,
-        "K" => 0x4B,
-        "L" => 0x4C,
-        "M" => 0x4D,
-        "N" => 0x4E,
-        "O" => 0x4F,
-        "P" => 0x50,
-        "Q" => 0x51,
-        "R" => 0x52,
-        "S" => 0x53,
-        "T" => 0x54,
-        "U" => 0x55,
-        "V" => 0x56,
-        "W" => 0x57,
-        "X" => 0x58,
-        "Y" => 0x59,
-        "Z" => 0x5A,
-
-        // Numbers
-        "0" => 0x30,
-        "1" => 0x31,
-        "2" => 0x32,
-        "3" => 0x33,
-        "4" => 0x34,
-        "5" => 0x35,
-        "6" => 0x36,
-        "7" => 0x37,
-        "8" => 0x38,
-        "9" => 0x39,
-
-        // Function Keys
-        "F1" => 0x70,
-        "F2" => 0x71,
-        "F3" => 0x72,
-        "F4" => 0x73,
-        "F5" => 0x74,
-        "F6" => 0x75,
-        "F7" => 0x76,
-        "F8" => 0x77,
-        "F9" => 0x78,
-        "F10" => 0x79,
-        "F11" => 0x7A,
-        "F12" => 0x7B,
-
-        // Numpad Keys
-        "NUMPAD0" => 0x60,
-        "NUMPAD1" => 0x61,
-        "NUMPAD2" => 0x62,
-        "NUMPAD3" => 0x63,
-        "NUMPAD4" => 0x64,
-        "NUMPAD5" => 0x65,
-        "NUMPAD6" => 0x66,
-        "NUMPAD7" => 0x67,
-        "NUMPAD8" => 0x68,
-        "NUMPAD9" => 0x69,
-        "MULTIPLY" => 0x6A,
-        "ADD" => 0x6B,
-        "SEPARATOR" => 0x6C,
-        "SUBTRACT" => 0x6D,
-        "DECIMAL" => 0x6E,
-        "DIVIDE" => 0x6F,
-
-        // Special Characters
-        ";" => 0xBA,  // VK_OEM_1
-        ":" => 0xBA,  // Shift + VK_OEM_1
-        "=" => 0xBB,  // VK_OEM_PLUS
-        "+" => 0xBB,  // Shift + VK_OEM_PLUS
-        "," => 0xBC,  // VK_OEM_COMMA
-        "<" => 0xBC,  // Shift + VK_OEM_COMMA
-        "-" => 0xBD,  // VK_OEM_MINUS
-        "_" => 0xBD,  // Shift + VK_OEM_MINUS
-        "." => 0xBE,  // VK_OEM_PERIOD
-        ">" => 0xBE,  // Shift + VK_OEM_PERIOD
-        "/" => 0xBF,  // VK_OEM_2
-        "?" => 0xBF,  // Shift + VK_OEM_2
-        "`" => 0xC0,  // VK_OEM_3
-        "~" => 0xC0,  // Shift + VK_OEM_3
-        "[" => 0xDB,  // VK_OEM_4
-        "{" => 0xDB,  // Shift + VK_OEM_4
-        "\\" => 0xDC, // VK_OEM_5
-        "|" => 0xDC,  // Shift + VK_OEM_5
-        "]" => 0xDD,  // VK_OEM_6
-        "}" => 0xDD,  // Shift + VK_OEM_6
-        "'" => 0xDE,  // VK_OEM_7
-        "\"" => 0xDE, // Shift + VK_OEM_7
-
-        // Control Keys
-        "BACKSPACE" => 0x08,
-        "TAB" => 0x09,
-        "CLEAR" => 0x0C,
-        "ENTER" => 0x0D,
-        "SHIFT" => 0x10,
-        "CTRL" => 0x11,
-        "ALT" => 0x12,
-        "PAUSE" => 0x13,
-        "CAPSLOCK" => 0x14,
-        "ESC" => 0x1B,
-        "SPACEBAR" => 0x20,
-        "PAGEUP" => 0x21,
-        "PAGEDOWN" => 0x22,
-        "END" => 0x23,
-        "HOME" => 0x24,
-        "LEFT" => 0x25,
-        "UP" => 0x26,
-        "RIGHT" => 0x27,
-        "DOWN" => 0x28,
-        "SELECT" => 0x29,
-        "PRINT" => 0x2A,
-        "EXECUTE" => 0x2B,
-        "PRINTSCREEN" => 0x2C,
-        "INSERT" => 0x2D,
-        "DELETE" => 0x2E,
-        "HELP" => 0x2F,
-
-        // Windows Keys
-        "LWIN" => 0x5B,
-        "RWIN" => 0x5C,
-        "WINDOWS" => 0x5B | 0x5C | 0x5D,
-        "APPS" => 0x5D,
-
-        // Numeric Keypad
-        "NUMLOCK" => 0x90,
-        "SCROLLLOCK" => 0x91,
-
-        // Media Keys
-        "VOLUME_MUTE" => 0xAD,
-        "VOLUME_DOWN" => 0xAE,
-        "VOLUME_UP" => 0xAF,
+/// The virtual key code for a key name from the config, if the name is known.
+pub fn try_string_to_vk(s: &str) -> Option<u32> {
+    KEY_NAMES
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, vk_code)| *vk_code)
+}
 
-        _ => s.chars().next().unwrap() as u32,
+/// Like `try_string_to_vk`, but falls back to the code point of the first character.
+pub fn string_to_vk(s: &str) -> u32 {
+    try_string_to_vk(s).unwrap_or_else(|| s.chars().next().unwrap() as u32)
+}
+/// The US-layout key and whether shift is needed to type `c`, for plain ASCII text.
+pub fn char_to_key_tap(c: char) -> Option<(u32, bool)> {
+    let shifted_digits = ")!@#$%^&*(";
+    match c {
+        'a'..='z' => Some((c.to_ascii_uppercase() as u32, false)),
+        'A'..='Z' => Some((c as u32, true)),
+        '0'..='9' => Some((c as u32, false)),
+        ' ' => Some((0x20, false)),
+        '\t' => Some((0x09, false)),
+        '\n' => Some((0x0D, false)),
+        _ => {
+            if let Some(digit) = shifted_digits.find(c) {
+                return Some(('0' as u32 + digit as u32, true));
+            }
+            // The special characters come in unshifted/shifted pairs.
+            let index = KEY_NAMES
+                .iter()
+                .position(|(name, _)| name.chars().eq([c]))?;
+            let (_, vk_code) = KEY_NAMES[index];
+            let is_shifted = index > 0 && KEY_NAMES[index - 1].1 == vk_code;
+            Some((vk_code, is_shifted))
+        }
     }
 }
 
+/// The printable ASCII character a US layout types for a key tap, if any.
+pub fn key_tap_to_char(vk_code: u32, is_shifted: bool) -> Option<char> {
+    (' '..='~').find(|c| char_to_key_tap(*c) == Some((vk_code, is_shifted)))
+}
+
 pub fn char_to_vk(s: char) -> u32 {
     //convert s to a string
     let s = s.to_string();
@@ -152,7 +182,6 @@ pub fn char_to_vk(s: char) -> u32 {
     string_to_vk(&s)
 }
 pub fn vk_to_string(vk_code: u32) -> Option<String> {
-    println!("vk_code: {}", vk_code);
     match vk_code {
         0x41 => Some("A".to_string()),
         0x42 => Some("B".to_string()),
@@ -180,7 +209,7 @@ pub fn vk_to_string(vk_code: u32) -> Option<String> {
         0x58 => Some("X".to_string()),
         0x59 => Some("Y".to_string()),
         0x5A => Some("Z".to_string()),
-        0x60 => Some("`".to_string()),
+        0x30 => Some("0".to_string()),
         0x31 => Some("1".to_string()),
         0x32 => Some("2".to_string()),
         0x33 => Some("3".to_string()),
@@ -190,7 +219,6 @@ pub fn vk_to_string(vk_code: u32) -> Option<String> {
         0x37 => Some("7".to_string()),
         0x38 => Some("8".to_string()),
         0x39 => Some("9".to_string()),
-        0x40 => Some("0".to_string()),
         0xBD => Some("-".to_string()),
         0xBB => Some("=".to_string()),
         0xDB => Some("[".to_string()),
@@ -198,12 +226,12 @@ pub fn vk_to_string(vk_code: u32) -> Option<String> {
         0xDC => Some("\\".to_string()),
         0xBA => Some(";".to_string()),
         0xDE => Some("'".to_string()),
-        0x72 => Some("~".to_string()),
+        0x72 => Some("F3".to_string()),
         0xBC => Some(",".to_string()),
         0xBE => Some(".".to_string()),
         0xBF => Some("/".to_string()),
         0xC0 => Some("`".to_string()),
-        0x7C => Some("|".to_string()),
+        0x7C => Some("F13".to_string()),
         // SHIFT
         0x10 | 0xA0 | 0xA1 => Some("SHIFT".to_string()),
         // CTRL
@@ -211,11 +239,7 @@ pub fn vk_to_string(vk_code: u32) -> Option<String> {
         // ALT
         0x12 | 0xA4 | 0xA5 => Some("ALT".to_string()),
         // WINDOWS
-        0x5B | 0x5C | 0x5D => Some("WINDOWS".to_string()),
-        // LWIN
-        0x5B => Some("LWIN".to_string()),
-        // RWIN
-        0x5C => Some("RWIN".to_string()),
+        0x5B..=0x5D => Some("WINDOWS".to_string()),
         // TAB
         0x09 => Some("TAB".to_string()),
         // ENTER
@@ -261,30 +285,74 @@ pub fn vk_to_string(vk_code: u32) -> Option<String> {
         // PRINT SCREEN
         _ => None,
     }
-    .inspect(|s| println!("That vk code translates to: the {:?} key.", s))
 }
 
+/// Every modifier name accepted in the config, with its virtual key code.
+pub const MODIFIER_NAMES: &[(&str, u32)] = &[
+    ("SHIFT", 0x10),  // VK_SHIFT
+    ("CTRL", 0x11),   // VK_CONTROL
+    ("ALT", 0x12),    // VK_ALT
+    ("LSHIFT", 0xA0), // VK_LSHIFT
+    ("RSHIFT", 0xA1), // VK_RSHIFT
+    ("LCTRL", 0xA2),  // VK_LCONTROL
+    ("RCTRL", 0xA3),  // VK_RCONTROL
+    ("LALT", 0xA4),   // VK_LALT
+    ("RALT", 0xA5),
+    ("LWIN", 0x5B),
+    ("RWIN", 0x5C),
+    ("WINDOWS", 0x5B | 0x5C | 0x5D),
+];
+
+/// The virtual key code for a modifier name, ignoring case and surrounding spaces.
+pub fn try_string_to_modifier(s: &str) -> Option<u32> {
+    let s = s.trim().to_uppercase();
+    MODIFIER_NAMES
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, vk_code)| *vk_code)
+}
+
+/// Like `try_string_to_modifier`, but unknown names give 0.
 pub fn string_to_modifier(s: &str) -> u32 {
-    // to uppercase
-    // using let statements because I care more about the name than the type.
-    let s = s.to_string();
-    let s = s.to_uppercase();
-    let s = s.trim();
-    match s {
-        "SHIFT" => 0x10,  // VK_SHIFT
-        "CTRL" => 0x11,   // VK_CONTROL
-        "ALT" => 0x12,    // VK_ALT
-        "LSHIFT" => 0xA0, // VK_LSHIFT
-        "RSHIFT" => 0xA1, // VK_RSHIFT
-        "LCTRL" => 0xA2,  // VK_LCONTROL
-        "RCTRL" => 0xA3,  // VK_RCONTROL
-        "LALT" => 0xA4,   // VK_LALT
-        "RALT" => 0xA5,
-        "LWIN" => 0x5B,
-        "RWIN" => 0x5C,
-        "WINDOWS" => 0x5B | 0x5C | 0x5D,
-        _ => 0,
+    try_string_to_modifier(s).unwrap_or(0)
+}
+
+/// Known names that look like `token`, closest first, for "did you mean" hints.
+pub fn suggest_names(token: &str, names: &'static [(&'static str, u32)]) -> Vec<&'static str> {
+    let upper = token.trim().to_uppercase();
+    // Single characters only match themselves in another case; everything is one edit away.
+    let max_distance = if upper.chars().count() <= 2 { 0 } else { 2 };
+    let mut scored: Vec<(usize, &'static str)> = Vec::new();
+    for (name, _) in names.iter() {
+        let is_prefix = name.len() >= 3 && (name.starts_with(&upper) || upper.starts_with(name));
+        let distance = if *name == upper {
+            0
+        } else if is_prefix {
+            1
+        } else {
+            edit_distance(&upper, name)
+        };
+        if distance <= max_distance {
+            scored.push((distance, name));
+        }
+    }
+    scored.sort();
+    scored.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
     }
+    previous[b.len()]
 }
 pub fn modifer_to_string_or_none(s: u32) -> Option<String> {
     match s {
@@ -303,3 +371,27 @@ pub fn modifer_to_string_or_none(s: u32) -> Option<String> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_characters_map_to_us_layout_keys() {
+        assert_eq!(char_to_key_tap('q'), Some((0x51, false)));
+        assert_eq!(char_to_key_tap('Q'), Some((0x51, true)));
+        assert_eq!(char_to_key_tap('@'), Some((0x32, true)));
+        assert_eq!(char_to_key_tap(';'), Some((0xBA, false)));
+        assert_eq!(char_to_key_tap(':'), Some((0xBA, true)));
+        assert_eq!(char_to_key_tap('"'), Some((0xDE, true)));
+        assert_eq!(char_to_key_tap('é'), None);
+    }
+
+    #[test]
+    fn key_taps_map_back_to_characters() {
+        assert_eq!(key_tap_to_char(0x39, true), Some('('));
+        assert_eq!(key_tap_to_char(0xDB, false), Some('['));
+        assert_eq!(key_tap_to_char(0xDE, true), Some('"'));
+        assert_eq!(key_tap_to_char(0x25, false), None);
+    }
+}
@@ -0,0 +1,2122 @@
+// engine.rs
+//! The platform-free core of bushido keys.
+//!
+//! The engine owns the list of available modes, the stack of active modes and
+//! the per-key state. Backends translate their native events into `KeyEvent`s,
+//! hand them to `Engine::process` and act on the returned `Decision`.
+use crate::app_context::{AppContext, ContextProvider, NoContext};
+use crate::clock::{Clock, SystemClock};
+use crate::input_simulator::get_char_from_vk_code;
+use crate::input_sink::{Action, InputSink, MouseButton, RecordingSink};
+use crate::key_state::{self, KeyState};
+use crate::mode::Mode;
+use crate::mode_config::{ActivationStyle, TapHoldStrategy, DEFAULT_TAP_TIMEOUT_MS};
+use crate::outgoing_event::OutgoingEventQueue;
+use crate::stats::{self, UsageStats};
+use log::{debug, info};
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A key going down or up, already stripped of any platform specifics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub vk_code: u32,
+    pub is_key_down: bool,
+    /// When the event happened. Backends stamp this with `Engine::now` as the event arrives.
+    pub time: Instant,
+}
+
+impl KeyEvent {
+    pub fn new(vk_code: u32, is_key_down: bool, time: Instant) -> Self {
+        Self {
+            vk_code,
+            is_key_down,
+            time,
+        }
+    }
+}
+
+/// The outcome of processing a single key event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// Whether the original event should be passed on to the system.
+    pub propagate: bool,
+    /// Events to synthesize, in order.
+    pub actions: Vec<Action>,
+}
+
+impl Decision {
+    fn new(propagate: bool, actions: Vec<Action>) -> Self {
+        Self { propagate, actions }
+    }
+}
+
+/// Key downs held back while they may still become an activation chord.
+struct PendingChord {
+    events: Vec<KeyEvent>,
+}
+
+impl PendingChord {
+    fn keys(&self) -> Vec<u32> {
+        self.events.iter().map(|event| event.vk_code).collect()
+    }
+}
+
+/// Events held back while a freshly pressed activation key may still turn out to be a tap.
+struct PendingHold {
+    activator: u32,
+    pressed_at: Instant,
+    events: Vec<KeyEvent>,
+}
+
+impl PendingHold {
+    /// Whether a key went down and came back up while the activation key was held.
+    fn has_inner_tap(&self) -> bool {
+        self.events.iter().enumerate().any(|(i, up)| {
+            !up.is_key_down
+                && self.events[..i]
+                    .iter()
+                    .any(|down| down.is_key_down && down.vk_code == up.vk_code)
+        })
+    }
+}
+
+/// Keys the engine forwards untouched: backspace, arrows and modifiers other than shift.
+fn is_passed_through(vk_code: u32) -> bool {
+    matches!(vk_code, 0x08 | 0x25 | 0x26 | 0x27 | 0x28)
+        || (crate::conversion::modifer_to_string_or_none(vk_code).is_some()
+            && !matches!(vk_code, 0x10 | 0xA0 | 0xA1))
+}
+
+/// Whether releasing `vk_code` ends `mode` because it is one of the keys of the chord that activated it.
+fn ends_chord(mode: &(dyn Mode + Send), vk_code: u32) -> bool {
+    let chord = mode.get_activation_chord();
+    !mode.is_locked()
+        && chord.contains(&vk_code)
+        && mode
+            .get_activated_by()
+            .is_some_and(|activator| chord.contains(&activator))
+}
+
+pub struct Engine {
+    /// Available modes loaded from configuration.
+    available_modes: Vec<Box<dyn Mode + Send>>,
+    /// Active modes, bottom first. Holding another mode's activation key while a mode
+    /// is active pushes that mode on top; releasing it removes it wherever it is.
+    mode_stack: Vec<Box<dyn Mode + Send>>,
+    key_states: HashMap<i32, KeyState>,
+    /// Modes reloaded while a mode was active, swapped in once it deactivates.
+    pending_modes: Option<Vec<Box<dyn Mode + Send>>>,
+    pending_chord: Option<PendingChord>,
+    pending_hold: Option<PendingHold>,
+    /// The last key that went down, for counting repeated taps.
+    last_pressed: Option<u32>,
+    /// When the last key typed outside any mode went down.
+    last_keystroke: Option<Instant>,
+    /// How long before the current key down that was, for modes that require idle.
+    idle_for: Option<Duration>,
+    /// Output of overlapping keys, held back until it can go out in press order.
+    outgoing: OutgoingEventQueue,
+    clock: Arc<dyn Clock>,
+    context: Arc<dyn ContextProvider>,
+    /// The focused application as of the last new key press, asked for only once a mode
+    /// with an `app_match` may activate.
+    app_context: OnceCell<Option<AppContext>>,
+    /// Counts since the statistics were last taken.
+    stats: UsageStats,
+    /// Every key event passes through untouched.
+    suspended: bool,
+    /// Keys and mouse buttons the output pressed and hasn't released yet.
+    output_keys_down: Vec<u32>,
+    output_buttons_down: Vec<MouseButton>,
+    /// Keys that are down, passed-through ones included, to tell auto-repeat from a press.
+    keys_down: HashSet<u32>,
+}
+
+impl Engine {
+    pub fn new(available_modes: Vec<Box<dyn Mode + Send>>) -> Self {
+        Self::with_clock(available_modes, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(available_modes: Vec<Box<dyn Mode + Send>>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            available_modes,
+            mode_stack: Vec::new(),
+            key_states: HashMap::new(),
+            pending_modes: None,
+            pending_chord: None,
+            pending_hold: None,
+            last_pressed: None,
+            last_keystroke: None,
+            idle_for: None,
+            outgoing: OutgoingEventQueue::new(),
+            clock,
+            context: Arc::new(NoContext),
+            app_context: OnceCell::new(),
+            stats: UsageStats::default(),
+            suspended: false,
+            output_keys_down: Vec::new(),
+            output_buttons_down: Vec::new(),
+            keys_down: HashSet::new(),
+        }
+    }
+
+    /// Where the engine learns which application has the focus, for modes with an `app_match`.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn set_context_provider(&mut self, context: Arc<dyn ContextProvider>) {
+        self.context = context;
+    }
+
+    /// The current time according to the engine's clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn set_available_modes(&mut self, available_modes: Vec<Box<dyn Mode + Send>>) {
+        self.available_modes = available_modes;
+    }
+
+    /// Replace the available modes as soon as no mode is active,
+    /// so a held activation key is still released by the mode it activated.
+    pub fn reload_modes(&mut self, available_modes: Vec<Box<dyn Mode + Send>>) {
+        if !self.mode_stack.is_empty() {
+            info!("A mode is active, reloading the modes once it deactivates");
+            self.pending_modes = Some(available_modes);
+        } else {
+            self.available_modes = available_modes;
+            self.pending_modes = None;
+        }
+    }
+
+    /// Stop remapping until `resume`. Active modes are left, typing what they still held
+    /// back and the output already decided; undecided keys are dropped. The returned
+    /// actions end with releasing every key and mouse button the output still holds down.
+    pub fn suspend(&mut self) -> Vec<Action> {
+        info!("Suspended");
+        self.suspended = true;
+        let mut actions = self.outgoing.take_decided();
+        let mut sink = RecordingSink::new();
+        while let Some(layer) = self.mode_stack.len().checked_sub(1) {
+            self.remove_layer(layer, &mut sink);
+        }
+        actions.extend(sink.actions);
+        self.note_output(&actions);
+        actions.extend(self.output_keys_down.drain(..).map(Action::KeyUp));
+        actions.extend(
+            self.output_buttons_down
+                .drain(..)
+                .map(|button| Action::MouseButton {
+                    button,
+                    down: false,
+                }),
+        );
+        self.key_states.clear();
+        self.keys_down.clear();
+        self.pending_chord = None;
+        self.pending_hold = None;
+        if let Some(available_modes) = self.pending_modes.take() {
+            self.available_modes = available_modes;
+        }
+        actions
+    }
+
+    /// Keep track of what the output holds down.
+    fn note_output(&mut self, actions: &[Action]) {
+        for action in actions.iter() {
+            match action {
+                Action::KeyDown(vk_code) if !self.output_keys_down.contains(vk_code) => {
+                    self.output_keys_down.push(*vk_code)
+                }
+                Action::KeyUp(vk_code) => self.output_keys_down.retain(|down| down != vk_code),
+                Action::MouseButton { button, down: true }
+                    if !self.output_buttons_down.contains(button) =>
+                {
+                    self.output_buttons_down.push(*button)
+                }
+                Action::MouseButton {
+                    button,
+                    down: false,
+                } => self.output_buttons_down.retain(|down| down != button),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn resume(&mut self) {
+        info!("Resumed");
+        self.suspended = false;
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Lock the available mode `name` on, as if its activation key had been tapped.
+    /// Tapping that key, or the mode's exit key, leaves it again.
+    pub fn activate_mode(&mut self, name: &str) -> Result<(), String> {
+        if self.suspended {
+            return Err("suspended".to_string());
+        }
+        if self.mode_stack.iter().any(|mode| mode.get_name() == name) {
+            return Err(format!("mode \"{}\" is already active", name));
+        }
+        let mut mode = self
+            .available_modes
+            .iter()
+            .find(|mode| mode.get_name() == name)
+            .cloned()
+            .ok_or_else(|| format!("no mode named \"{}\"", name))?;
+        let activator = mode
+            .get_activation_keys()
+            .first()
+            .or(mode.get_activation_chord().first())
+            .copied();
+        if let Some(activator) = activator {
+            mode.set_activated_by(activator);
+        }
+        mode.set_locked_at(Some(self.clock.now()));
+        info!("Locking mode {} on request", name);
+        self.push_mode(mode);
+        Ok(())
+    }
+
+    /// The topmost active mode.
+    #[cfg(test)]
+    pub fn current_mode_name(&self) -> Option<&str> {
+        self.mode_stack.last().map(|mode| mode.get_name())
+    }
+
+    /// The names of all active modes, bottom first.
+    pub fn active_mode_names(&self) -> Vec<&str> {
+        self.mode_stack.iter().map(|mode| mode.get_name()).collect()
+    }
+
+    /// Run the periodic update of the active modes, e.g. mouse movement.
+    pub fn update(&mut self) -> Vec<Action> {
+        let actions = self.update_modes();
+        self.note_output(&actions);
+        actions
+    }
+
+    fn update_modes(&mut self) -> Vec<Action> {
+        if self.suspended {
+            return Vec::new();
+        }
+        let now = self.clock.now();
+        self.expire_one_shots(now);
+        let mut actions = self.expire_chord(now);
+        actions.extend(self.resolve_hold(now));
+        let mut sink = RecordingSink::new();
+        for mode in self.mode_stack.iter_mut() {
+            mode.update(now, &mut sink);
+        }
+        actions.extend(sink.actions);
+        self.count_used_mappings();
+        self.remove_finished_layers(&mut actions);
+        self.outgoing.expire(now);
+        actions.extend(self.outgoing.take_ready());
+        actions
+    }
+
+    /// Take the usage statistics counted since the last call.
+    pub fn take_stats(&mut self) -> UsageStats {
+        self.count_used_mappings();
+        std::mem::take(&mut self.stats)
+    }
+
+    fn count_used_mappings(&mut self) {
+        for mode in self.mode_stack.iter_mut() {
+            let used = mode.take_used_mappings();
+            self.stats.count_mappings(mode.get_name(), used);
+        }
+    }
+
+    /// Count a new press of `vk_code` for the layer that will handle it, or for no mode.
+    fn count_key_press(&mut self, vk_code: u32) {
+        let name = stats::key_name(vk_code);
+        let counts = if self.mode_stack.is_empty() {
+            &mut self.stats.keys
+        } else {
+            let layer = self.layer_for(vk_code);
+            &mut self.stats.mode(self.mode_stack[layer].get_name()).keys
+        };
+        *counts.entry(name).or_default() += 1;
+    }
+
+    fn push_mode(&mut self, mode: Box<dyn Mode + Send>) {
+        self.stats.mode(mode.get_name()).activations += 1;
+        self.mode_stack.push(mode);
+    }
+
+    /// Available modes whose activation chord includes all of `keys`.
+    fn chord_candidates<'a>(
+        &'a self,
+        keys: &'a [u32],
+    ) -> impl Iterator<Item = &'a Box<dyn Mode + Send>> + 'a {
+        self.available_modes.iter().filter(move |mode| {
+            let chord = mode.get_activation_chord();
+            !chord.is_empty()
+                && keys.iter().all(|key| chord.contains(key))
+                && self.may_activate(mode.as_ref())
+        })
+    }
+
+    /// Whether `mode` may activate in the focused application, and this soon after typing.
+    fn may_activate(&self, mode: &(dyn Mode + Send)) -> bool {
+        let typing = mode
+            .get_activation()
+            .require_idle
+            .is_some_and(|idle| self.idle_for.is_some_and(|idle_for| idle_for < idle));
+        !typing
+            && mode.get_app_filter().is_none_or(|filter| {
+                let app_context = self.app_context.get_or_init(|| self.context.current());
+                filter.allows(app_context.as_ref())
+            })
+    }
+
+    /// Hold back a key down that starts or continues an activation chord,
+    /// activating the mode once the chord is complete.
+    /// Returns false if the event has nothing to do with a chord.
+    fn absorb_into_chord(&mut self, event: KeyEvent) -> bool {
+        if !event.is_key_down {
+            return false;
+        }
+        let mut keys = match &self.pending_chord {
+            Some(pending) => pending.keys(),
+            None => {
+                let any_key_down = self.key_states.values().any(|state| state.held);
+                if !self.mode_stack.is_empty() || any_key_down {
+                    return false;
+                }
+                Vec::new()
+            }
+        };
+        if keys.contains(&event.vk_code) {
+            // The system repeating a held chord key.
+            return true;
+        }
+        keys.push(event.vk_code);
+        if self.chord_candidates(&keys).next().is_none() {
+            return false;
+        }
+        let complete = self
+            .chord_candidates(&keys)
+            .find(|mode| mode.get_activation_chord().len() == keys.len())
+            .cloned();
+        let mut pending = self
+            .pending_chord
+            .take()
+            .unwrap_or(PendingChord { events: Vec::new() });
+        pending.events.push(event);
+        let Some(mut mode) = complete else {
+            self.pending_chord = Some(pending);
+            return true;
+        };
+        info!(
+            "Chord {:?} complete, setting current mode to {}",
+            keys,
+            mode.get_name()
+        );
+        mode.set_activated_by(keys[0]);
+        for event in pending.events {
+            let mut state = KeyState::new(event.vk_code as i32, event.time);
+            state.held = true;
+            state.prev_held = true;
+            self.key_states.insert(state.vk_code, state);
+        }
+        self.push_mode(mode);
+        true
+    }
+
+    /// Give up on a pending chord whose window has passed, typing its keys normally.
+    fn expire_chord(&mut self, now: Instant) -> Vec<Action> {
+        let Some(pending) = &self.pending_chord else {
+            return Vec::new();
+        };
+        let keys = pending.keys();
+        let window = self
+            .chord_candidates(&keys)
+            .map(|mode| mode.get_chord_window())
+            .max()
+            .unwrap_or(Duration::ZERO);
+        if now.duration_since(pending.events[0].time) < window {
+            return Vec::new();
+        }
+        self.flush_chord()
+    }
+
+    /// Process the held back key downs as if no chord had been configured.
+    fn flush_chord(&mut self) -> Vec<Action> {
+        let Some(pending) = self.pending_chord.take() else {
+            return Vec::new();
+        };
+        info!("Chord {:?} not completed, typing its keys", pending.keys());
+        pending
+            .events
+            .into_iter()
+            .flat_map(|event| self.process_held(event).actions)
+            .collect()
+    }
+
+    /// Start holding back events after `mode` was pushed, if its strategy needs to see
+    /// what happens next before deciding between tap and hold.
+    fn start_hold(&mut self, mode: &(dyn Mode + Send), activator: u32, now: Instant) {
+        if mode.get_activation().tap_hold != TapHoldStrategy::Timeout {
+            self.pending_hold = Some(PendingHold {
+                activator,
+                pressed_at: now,
+                events: Vec::new(),
+            });
+        }
+    }
+
+    /// Whether the pending activation key is now known to be held.
+    fn is_hold_decided(&self, now: Instant) -> bool {
+        let Some(pending) = &self.pending_hold else {
+            return false;
+        };
+        let Some(mode) = self
+            .mode_stack
+            .iter()
+            .find(|mode| mode.get_activated_by() == Some(pending.activator))
+        else {
+            return true;
+        };
+        let held_for = now.duration_since(pending.pressed_at);
+        if held_for >= mode.get_tap_timeout() {
+            return true;
+        }
+        let activation = mode.get_activation();
+        match activation.tap_hold {
+            TapHoldStrategy::Timeout => true,
+            TapHoldStrategy::HoldOnOtherKeyPress => {
+                pending.events.iter().any(|event| event.is_key_down)
+            }
+            TapHoldStrategy::PermissiveHold => pending.has_inner_tap(),
+            TapHoldStrategy::Balanced => {
+                held_for >= activation.balanced_min && pending.has_inner_tap()
+            }
+        }
+    }
+
+    /// Once the pending activation key is a hold, hand the held back events to its mode.
+    fn resolve_hold(&mut self, now: Instant) -> Vec<Action> {
+        if !self.is_hold_decided(now) {
+            return Vec::new();
+        }
+        let Some(pending) = self.pending_hold.take() else {
+            return Vec::new();
+        };
+        if let Some(mode) = self
+            .mode_stack
+            .iter_mut()
+            .find(|mode| mode.get_activated_by() == Some(pending.activator))
+        {
+            info!(
+                "{:#X} is held, resuming in {}",
+                pending.activator,
+                mode.get_name()
+            );
+            mode.set_was_mode_used(true);
+        }
+        self.replay(pending.events)
+    }
+
+    /// Process held back events in their original order.
+    fn replay(&mut self, events: Vec<KeyEvent>) -> Vec<Action> {
+        events
+            .into_iter()
+            .flat_map(|event| self.process_held(event).actions)
+            .collect()
+    }
+
+    /// Hold back events while an activation key is undecided between tap and hold.
+    fn process_held(&mut self, event: KeyEvent) -> Decision {
+        let Some(pending) = self.pending_hold.as_mut() else {
+            return self.process_key(event);
+        };
+        if event.vk_code == pending.activator {
+            if event.is_key_down {
+                return Decision::new(false, Vec::new());
+            }
+            // Released before it became a hold: the mode types the key and the
+            // held back keys are typed after it, as if the mode was never active.
+            let events = std::mem::take(&mut pending.events);
+            self.pending_hold = None;
+            let decision = self.process_key(event);
+            let mut actions = decision.actions;
+            actions.extend(self.replay(events));
+            return Decision::new(decision.propagate, actions);
+        }
+        if is_passed_through(event.vk_code) {
+            return self.process_key(event);
+        }
+        pending.events.push(event);
+        Decision::new(false, self.resolve_hold(event.time))
+    }
+
+    /// Type a key through the active modes as a tap, or directly if none is active.
+    fn type_key(&mut self, state: &KeyState, sink: &mut RecordingSink) {
+        let vk_code = state.vk_code as u32;
+        if self.mode_stack.is_empty() {
+            info!("Simulating key tap of activation key");
+            sink.key_tap(vk_code, &[]);
+            return;
+        }
+        let layer = self.layer_for(vk_code);
+        let mode = &mut self.mode_stack[layer];
+        let mut pressed = state.clone();
+        pressed.held = true;
+        mode.handle_key_down_event(&mut pressed, sink);
+        mode.handle_key_up_event(&mut state.clone(), sink);
+    }
+
+    /// The layer that gets a key: the topmost one that maps it, otherwise the top.
+    /// Only call this while a mode is active.
+    fn layer_for(&self, vk_code: u32) -> usize {
+        self.mode_stack
+            .iter()
+            .rposition(|mode| mode.maps_key(vk_code))
+            .unwrap_or(self.mode_stack.len() - 1)
+    }
+
+    /// An inactive mode that `vk_code` activates in the focused application.
+    /// Keys mapped by an active layer keep their mapping instead.
+    fn mode_to_stack(&self, vk_code: u32) -> Option<Box<dyn Mode + Send>> {
+        if self.mode_stack.iter().any(|mode| mode.maps_key(vk_code)) {
+            return None;
+        }
+        // The activation key is checked first, so only a mode on this key asks for
+        // the focused application.
+        self.available_modes
+            .iter()
+            .find(|mode| {
+                mode.get_activation_keys().contains(&vk_code)
+                    && !self
+                        .mode_stack
+                        .iter()
+                        .any(|active| active.get_name() == mode.get_name())
+                    && self.may_activate(mode.as_ref())
+            })
+            .cloned()
+    }
+
+    /// The locked layer that `vk_code`, its activation or exit key, takes out of the stack.
+    fn locked_layer_left_by(&self, vk_code: u32) -> Option<usize> {
+        self.mode_stack.iter().position(|mode| {
+            mode.is_locked()
+                && (mode.get_activated_by() == Some(vk_code)
+                    || mode.get_activation().exit_key == Some(vk_code))
+        })
+    }
+
+    /// The topmost one-shot mode that was tapped and is waiting for its key.
+    fn armed_one_shot(&self) -> Option<usize> {
+        self.mode_stack.iter().rposition(|mode| {
+            mode.is_locked() && mode.get_activation().style == ActivationStyle::OneShot
+        })
+    }
+
+    /// Turn off tapped one-shot modes whose key didn't come in time.
+    fn expire_one_shots(&mut self, now: Instant) {
+        self.mode_stack.retain(|mode| {
+            let expired = mode.get_activation().style == ActivationStyle::OneShot
+                && mode.get_locked_at().is_some_and(|locked_at| {
+                    now.duration_since(locked_at) >= mode.get_activation().one_shot_timeout
+                });
+            if expired {
+                info!("One-shot mode {} timed out", mode.get_name());
+            }
+            !expired
+        });
+    }
+
+    /// Take a mode out of the stack, typing whatever it still held back.
+    /// Its held keys that already repeated type nothing on release.
+    fn remove_layer(&mut self, layer: usize, sink: &mut RecordingSink) -> Box<dyn Mode + Send> {
+        let mut mode = self.mode_stack.remove(layer);
+        mode.finish_pending(sink);
+        let used = mode.take_used_mappings();
+        self.stats.count_mappings(mode.get_name(), used);
+        for repeated in mode.take_repeated_keys() {
+            if let Some(repeated) = self.key_states.get_mut(&(repeated as i32)) {
+                repeated.consumed = true;
+            }
+        }
+        mode
+    }
+
+    /// Take out the layers that finished on their own. The keys still held that
+    /// went down in them, their activation key included, release silently.
+    fn remove_finished_layers(&mut self, actions: &mut Vec<Action>) {
+        while let Some(layer) = self.mode_stack.iter().position(|mode| mode.is_finished()) {
+            let mut sink = RecordingSink::new();
+            let mode = self.remove_layer(layer, &mut sink);
+            actions.extend(sink.actions);
+            info!("Mode {} finished", mode.get_name());
+            let activator = mode.get_activated_by();
+            for state in self.key_states.values_mut() {
+                if state.held
+                    && (state.layer == activator || Some(state.vk_code as u32) == activator)
+                {
+                    state.consumed = true;
+                }
+            }
+        }
+    }
+
+    /// Release the keys still held that went down in the layer activated by `activator`,
+    /// so their output comes from that mode; their real release then types nothing.
+    fn release_owned_keys(
+        &mut self,
+        mode: &mut (dyn Mode + Send),
+        activator: u32,
+        now: Instant,
+        sink: &mut RecordingSink,
+    ) {
+        let mut owned: Vec<&mut KeyState> = self
+            .key_states
+            .values_mut()
+            .filter(|state| state.held && !state.consumed && state.layer == Some(activator))
+            .collect();
+        owned.sort_by_key(|state| state.time_pressed);
+        for state in owned {
+            let mut released = state.clone();
+            released.held = false;
+            released.time_released = now;
+            mode.handle_key_up_event(&mut released, sink);
+            state.consumed = true;
+        }
+    }
+
+    /// Take care of loose ends before returning a decision: store the key state.
+    fn finish(&mut self, mut state: KeyState, propagate: bool, sink: RecordingSink) -> Decision {
+        if self.mode_stack.is_empty() {
+            if let Some(available_modes) = self.pending_modes.take() {
+                info!("Swapping in the reloaded modes");
+                self.available_modes = available_modes;
+            }
+        }
+        state.prev_held = state.held;
+        self.key_states.insert(state.vk_code, state);
+        Decision::new(propagate, sink.actions)
+    }
+
+    /// Decide what to do with a key event.
+    /// Key downs that may form an activation chord are held back until the chord
+    /// completes or fails; a failed chord is then processed key by key, in order.
+    pub fn process(&mut self, event: KeyEvent) -> Decision {
+        let decision = self.process_event(event);
+        self.note_output(&decision.actions);
+        decision
+    }
+
+    fn process_event(&mut self, event: KeyEvent) -> Decision {
+        if self.suspended {
+            return Decision::new(true, Vec::new());
+        }
+        if event.is_key_down {
+            self.stats.key_down_events += 1;
+            if self.keys_down.insert(event.vk_code) {
+                self.count_key_press(event.vk_code);
+                self.app_context = OnceCell::new();
+            }
+            self.idle_for = self
+                .last_keystroke
+                .map(|at| event.time.saturating_duration_since(at));
+        } else {
+            self.keys_down.remove(&event.vk_code);
+        }
+        self.expire_one_shots(event.time);
+        let mut actions = self.expire_chord(event.time);
+        actions.extend(self.resolve_hold(event.time));
+        if self.absorb_into_chord(event) {
+            self.stats.blocked_events += 1;
+            return Decision::new(false, actions);
+        }
+        actions.extend(self.flush_chord());
+        let decision = self.process_held(event);
+        actions.extend(decision.actions);
+        if !decision.propagate {
+            self.stats.blocked_events += 1;
+        }
+        self.count_used_mappings();
+        Decision::new(decision.propagate, actions)
+    }
+
+    /// Decide what to do with a key event that isn't part of a pending chord, keeping
+    /// the output of overlapping keys in the order they went down.
+    /// A key whose press is swallowed without output holds its place until its release
+    /// decides what it types; an activation key settles as typing nothing once its
+    /// mode, or one stacked on it, is used.
+    fn process_key(&mut self, event: KeyEvent) -> Decision {
+        let vk_code = match event.vk_code {
+            0xA0 | 0xA1 => 0x10,
+            vk_code => vk_code,
+        };
+        // Shift is left out, it would hold back everything typed while it is down.
+        let is_ordered = vk_code != 0x10 && !is_passed_through(vk_code);
+        let is_new_press = event.is_key_down
+            && !self
+                .key_states
+                .get(&(vk_code as i32))
+                .is_some_and(|state| state.held);
+        let mut decision = self.handle_key(event);
+        self.remove_finished_layers(&mut decision.actions);
+        if !is_ordered {
+            return decision;
+        }
+        self.outgoing.expire(event.time);
+        let mut actions = decision.actions;
+        if is_new_press && !decision.propagate && actions.is_empty() {
+            let max_wait = self
+                .mode_stack
+                .last()
+                .map_or(Duration::from_millis(DEFAULT_TAP_TIMEOUT_MS), |mode| {
+                    mode.get_tap_timeout()
+                });
+            self.outgoing.push(vk_code, event.time, max_wait);
+        } else if !event.is_key_down {
+            actions = self.outgoing.resolve(vk_code, actions);
+        }
+        // A used layer means it and every layer below it are being held.
+        if let Some(top_used) = self
+            .mode_stack
+            .iter()
+            .rposition(|mode| mode.was_mode_used())
+        {
+            for mode in self.mode_stack[..=top_used].iter() {
+                if let Some(activator) = mode.get_activated_by() {
+                    self.outgoing.cancel(activator);
+                }
+            }
+        }
+        actions.extend(self.outgoing.take_ready());
+        Decision::new(decision.propagate, actions)
+    }
+
+    /// Decide what to do with a single key event.
+    /// - It tracks key states.
+    /// - It passes a KeyState instance to mode event handlers.
+    fn handle_key(&mut self, event: KeyEvent) -> Decision {
+        let is_key_down = event.is_key_down;
+        let is_key_up = !event.is_key_down;
+        let now = event.time;
+
+        if is_key_up {
+            info!("👆 Key up event detected");
+        } else {
+            info!("👇 Key down event detected");
+        }
+
+        let mut vk_code = event.vk_code;
+        // left and right shift are tracked as the generic shift key
+        if vk_code == 0x10 || vk_code == 0xA0 || vk_code == 0xA1 {
+            vk_code = 0x10;
+        }
+        // backspace, arrows and modifiers other than shift are forwarded immediately
+        if is_passed_through(vk_code) {
+            return Decision::new(true, Vec::new());
+        }
+        let any_key_down = self.key_states.values().any(|state| state.held);
+        let was_pressed_before = self.key_states.contains_key(&(vk_code as i32));
+        let mut state = self
+            .key_states
+            .entry(vk_code as i32)
+            .or_insert_with(|| KeyState::new(vk_code as i32, now))
+            .clone();
+
+        state.held = is_key_down;
+
+        let is_system_repeat = state.prev_held && state.held;
+        if is_key_down && !is_system_repeat {
+            if was_pressed_before {
+                state.previous_press = Some((state.time_pressed, state.time_released));
+            }
+            state.time_pressed = now;
+            state.consumed = false;
+            state.ends_one_shot = false;
+            state.tap_count = if self.last_pressed == Some(vk_code) {
+                state.tap_count + 1
+            } else {
+                1
+            };
+            self.last_pressed = Some(vk_code);
+            // see if state of shift key is 'held'
+            match self.key_states.get(&0x10) {
+                Some(shift_state) => {
+                    if shift_state.held {
+                        if vk_code != 0x10 {
+                            state.was_shift_held_on_key_down = true;
+                        }
+                    } else {
+                        state.was_shift_held_on_key_down = false;
+                    }
+                }
+                None => {
+                    debug!("Shift is not pressed.");
+                }
+            }
+        } else if !is_key_down && !is_system_repeat {
+            state.time_released = now;
+        }
+
+        self.log_key_event(vk_code, is_key_down);
+
+        let mut sink = RecordingSink::new();
+
+        // Here are the possible outcomes:
+        // 1. No mode is active, and the key is not in the keymap of any mode. The key is forwarded.
+        // 2. There is an active mode, the key is in the keymap. The event is not propogated, and instead a key up or key down is simulated.
+        // 3. There is an active mode, its a key up event, the key matches the activation key. The mode is deactivated.
+        // 4. There is an active mode, its a key up event, the key does not match the activation key. The event is forwarded.
+        // 5. There is an active mode, the key is in the keymap of a mode. The mode is activated, and the key is not propagated.
+        //
+        // Key Up
+        //      Update the key state with prev state and current state.
+        //      If the key matches the activation key, and the mode is active,
+        //         Deactivate the mode.
+        //         Do not forward the event.
+        //         Check the timeout. If the key was held for less than timeout, simulate key press of activation key.
+        //      If there is an active mode, run the event through the key up event handler of the mode.
+        //      If there is no active mode, forward the event.
+        // Key Down:
+        //      If prev state is held
+        //         If there is an active mode, do not process the event.
+        //         We know it doesn't activate a mode, becauwse it's already held.
+        //      If prev state is not held,
+        //         update the key state with prev state and current state. mark the time pressed.
+        //         If there is an active mode, run the event through the key down event handler of the mode.
+        //         simplify things by not forwarding the event if there is an active mode, or if it activates or deactivates a mode.
+        if !self.mode_stack.is_empty() {
+            if is_system_repeat {
+                // Modes repeat mapped keys themselves from update().
+                return self.finish(state, false, sink);
+            }
+            if is_key_down {
+                state.time_pressed = now;
+                if self.locked_layer_left_by(vk_code).is_some() {
+                    // The release leaves the locked mode.
+                    state.layer = None;
+                    return self.finish(state, false, sink);
+                }
+                if let Some(layer) = self.armed_one_shot() {
+                    // This key uses the one-shot mode, which turns off once it's released.
+                    let mode = &mut self.mode_stack[layer];
+                    mode.set_locked_at(None);
+                    state.layer = mode.get_activated_by();
+                    state.ends_one_shot = true;
+                    let handled = mode.handle_key_down_event(&mut state, &mut sink);
+                    return self.finish(state, !handled, sink);
+                }
+                if let Some(mut mode) = self.mode_to_stack(vk_code) {
+                    info!(
+                        "Stacking mode {} on top of {:?}",
+                        mode.get_name(),
+                        self.active_mode_names()
+                    );
+                    mode.set_activated_by(vk_code);
+                    self.start_hold(mode.as_ref(), vk_code, now);
+                    self.push_mode(mode);
+                    state.layer = None;
+                    return self.finish(state, false, sink);
+                }
+                let layer = self.layer_for(vk_code);
+                let mode = &mut self.mode_stack[layer];
+                state.layer = mode.get_activated_by();
+                let handled = mode.handle_key_down_event(&mut state, &mut sink);
+                return self.finish(state, !handled, sink);
+            }
+            if let Some(layer) = self.locked_layer_left_by(vk_code) {
+                let mode = self.remove_layer(layer, &mut sink);
+                info!("Leaving locked mode {}", mode.get_name());
+                return self.finish(state, false, sink);
+            }
+            // A chord ends when any of its keys goes up.
+            let chord_layer = self
+                .mode_stack
+                .iter()
+                .position(|mode| ends_chord(mode.as_ref(), vk_code));
+            let layer = chord_layer.or_else(|| {
+                self.mode_stack
+                    .iter_mut()
+                    .position(|mode| mode.check_if_deactivates(&mut state))
+            });
+            if let Some(layer) = layer {
+                let mut mode = self.remove_layer(layer, &mut sink);
+                if mode.get_activation().tap_hold != TapHoldStrategy::Timeout {
+                    // Keys pressed while it was held still belong to the mode.
+                    self.release_owned_keys(mode.as_mut(), vk_code, now, &mut sink);
+                }
+                let mut keys = vec![vk_code];
+                if chord_layer.is_some() {
+                    keys = mode.get_activation_chord().to_vec();
+                    keys.sort_by_key(|key| {
+                        self.key_states
+                            .get(&(*key as i32))
+                            .map_or(now, |state| state.time_pressed)
+                    });
+                }
+                let pressed_at = self
+                    .key_states
+                    .get(&(keys[0] as i32))
+                    .map_or(state.time_pressed, |state| state.time_pressed);
+                let elapsed = now.duration_since(pressed_at);
+                let tap_timeout = mode.get_tap_timeout();
+                info!("Elapsed time since key down: {:?}", elapsed);
+                let typed = elapsed < tap_timeout && !mode.was_mode_used();
+                let activation = *mode.get_activation();
+                let double_tapped = state.previous_press.is_some_and(|(down, up)| {
+                    up.duration_since(down) < tap_timeout
+                        && state.time_pressed.duration_since(up) < activation.double_tap
+                });
+                let lock = typed
+                    && match activation.style {
+                        ActivationStyle::Hold => false,
+                        ActivationStyle::Toggle => true,
+                        ActivationStyle::DoubleTapLock => double_tapped,
+                        ActivationStyle::OneShot => true,
+                    };
+                let stats = self.stats.mode(mode.get_name());
+                if typed {
+                    stats.taps += 1;
+                } else {
+                    stats.holds += 1;
+                }
+                if typed && !lock {
+                    // The activation key was typed as a letter.
+                    self.last_keystroke = Some(pressed_at);
+                }
+                for &key in keys.iter() {
+                    if key == vk_code {
+                        if typed && !lock {
+                            self.type_key(&state, &mut sink);
+                        }
+                        continue;
+                    }
+                    // The other chord keys are typed in press order and release silently.
+                    let Some(other) = self.key_states.get_mut(&(key as i32)) else {
+                        continue;
+                    };
+                    other.consumed = other.held;
+                    let mut other = other.clone();
+                    other.time_released = now;
+                    if typed && !lock {
+                        self.type_key(&other, &mut sink);
+                    }
+                }
+                if lock {
+                    info!("Locking mode {}", mode.get_name());
+                    mode.set_locked_at(Some(now));
+                    mode.set_activated_by(keys[0]);
+                    self.mode_stack.insert(layer, mode);
+                    return self.finish(state, false, sink);
+                }
+                if typed {
+                    return self.finish(state, false, sink);
+                }
+                info!(
+                    "Key was held for more than {:?}, so not simulating key tap",
+                    tap_timeout
+                );
+                return self.finish(state, true, sink);
+            }
+            if state.consumed {
+                return self.finish(state, false, sink);
+            }
+            // The layer that saw the key go down, if it is still active.
+            let owner = self
+                .mode_stack
+                .iter()
+                .position(|mode| state.layer.is_some() && mode.get_activated_by() == state.layer);
+            let layer = owner.unwrap_or_else(|| self.layer_for(vk_code));
+            let handled = self.mode_stack[layer].handle_key_up_event(&mut state, &mut sink);
+            if let (true, Some(owner)) = (state.ends_one_shot, owner) {
+                let mode = self.remove_layer(owner, &mut sink);
+                info!("One-shot mode {} used", mode.get_name());
+            }
+            if !handled {
+                info!("forwarding the event because handle_key_up_event returned false");
+            }
+            return self.finish(state, !handled, sink);
+        }
+
+        // check if ANY key is down
+        if is_key_down && !is_system_repeat && !any_key_down {
+            if let Some(mut mode) = self.mode_to_stack(vk_code) {
+                mode.set_activated_by(vk_code);
+                state.time_pressed = now;
+                info!(
+                    "Detected a key down, it matches an activation key. Setting current mode to {}",
+                    mode.get_name()
+                );
+                self.start_hold(mode.as_ref(), vk_code, now);
+                self.push_mode(mode);
+                return self.finish(state, false, sink);
+            }
+        }
+        if is_key_down && !is_system_repeat && vk_code != 0x10 {
+            // A plain keystroke, for modes that require a pause in typing.
+            self.last_keystroke = Some(now);
+        } else if !is_key_down && !is_system_repeat && !state.consumed {
+            let mut modifiers = Vec::new();
+            if state.was_shift_held_on_key_down {
+                modifiers.push(0x10);
+            }
+            sink.key_tap(vk_code, &modifiers);
+        }
+        self.finish(state, false, sink)
+    }
+
+    fn log_key_event(&self, vk_code: u32, is_key_down: bool) {
+        // Get char from vk_code for logging
+        let char = get_char_from_vk_code(vk_code);
+        let active_modifiers = key_state::get_active_modifiers(&self.key_states);
+        let direction = if is_key_down { "pressed" } else { "released" };
+
+        // Log the event with modifier information
+        if !active_modifiers.is_empty() {
+            let modifier_names: Vec<String> = active_modifiers
+                .iter()
+                .map(|&m| match key_state::normalize_modifier(m) {
+                    key_state::VK_SHIFT => "SHIFT".to_string(),
+                    key_state::VK_CONTROL => "CTRL".to_string(),
+                    key_state::VK_ALT => "ALT".to_string(),
+                    _ => format!("MOD_{:X}", m),
+                })
+                .collect();
+            info!(
+                "{} key {} with modifiers: [{}]",
+                char,
+                direction,
+                modifier_names.join("+")
+            );
+        } else {
+            info!("{} key {}", char, direction);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_context::FakeContext;
+    use crate::basic_mode::BasicMode;
+    use crate::clock::ManualClock;
+    use crate::mode_config::{ModeConfig, ModesConfig};
+    use crate::test_utils::modes_with;
+    use std::time::Duration;
+
+    fn default_engine() -> Engine {
+        Engine::new(modes_with(|_| {}))
+    }
+
+    fn manual_engine() -> (Engine, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let mut engine = default_engine();
+        engine.clock = clock.clone();
+        (engine, clock)
+    }
+
+    fn key(engine: &mut Engine, vk_code: u32, is_key_down: bool, time: Instant) -> Decision {
+        engine.process(KeyEvent::new(vk_code, is_key_down, time))
+    }
+
+    #[test]
+    fn plain_key_is_tapped_on_release() {
+        let mut engine = default_engine();
+        let t0 = Instant::now();
+        let down = key(&mut engine, 'Q' as u32, true, t0);
+        assert!(!down.propagate);
+        assert!(down.actions.is_empty());
+        let up = key(
+            &mut engine,
+            'Q' as u32,
+            false,
+            t0 + Duration::from_millis(30),
+        );
+        assert_eq!(
+            up.actions,
+            vec![Action::KeyTap {
+                vk_code: 'Q' as u32,
+                modifiers: vec![]
+            }]
+        );
+    }
+
+    #[test]
+    fn mapped_key_in_mode_is_remapped() {
+        let mut engine = default_engine();
+        let t0 = Instant::now();
+        // A activates num_mode, D maps to 3
+        key(&mut engine, 'A' as u32, true, t0);
+        assert_eq!(engine.current_mode_name(), Some("num_mode"));
+        key(
+            &mut engine,
+            'D' as u32,
+            true,
+            t0 + Duration::from_millis(250),
+        );
+        let up = key(
+            &mut engine,
+            'D' as u32,
+            false,
+            t0 + Duration::from_millis(300),
+        );
+        assert!(!up.propagate);
+        assert_eq!(
+            up.actions,
+            vec![Action::KeyTap {
+                vk_code: '3' as u32,
+                modifiers: vec![]
+            }]
+        );
+        let release = key(
+            &mut engine,
+            'A' as u32,
+            false,
+            t0 + Duration::from_millis(400),
+        );
+        assert!(release.actions.is_empty());
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn quick_activation_tap_types_the_key() {
+        let mut engine = default_engine();
+        let t0 = Instant::now();
+        key(&mut engine, 'A' as u32, true, t0);
+        let up = key(
+            &mut engine,
+            'A' as u32,
+            false,
+            t0 + Duration::from_millis(80),
+        );
+        assert!(!up.propagate);
+        assert_eq!(
+            up.actions,
+            vec![Action::KeyTap {
+                vk_code: 'A' as u32,
+                modifiers: vec![]
+            }]
+        );
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn arrows_are_forwarded() {
+        let mut engine = default_engine();
+        let decision = key(&mut engine, 0x25, true, Instant::now());
+        assert_eq!(decision, Decision::new(true, vec![]));
+    }
+
+    #[test]
+    fn overlapping_keys_are_typed_in_press_order() {
+        let (mut engine, clock) = manual_engine();
+        let step = |engine: &mut Engine, vk_code: char, is_key_down: bool| {
+            clock.advance(Duration::from_millis(20));
+            key(engine, vk_code as u32, is_key_down, clock.now()).actions
+        };
+        // X is released first but went down after Z
+        step(&mut engine, 'Z', true);
+        step(&mut engine, 'X', true);
+        assert!(step(&mut engine, 'X', false).is_empty());
+        assert_eq!(typed(&step(&mut engine, 'Z', false)), "ZX");
+        // Same for a quick activation key pressed while a normal key is down
+        step(&mut engine, 'Z', true);
+        step(&mut engine, 'A', true);
+        assert!(step(&mut engine, 'A', false).is_empty());
+        assert_eq!(typed(&step(&mut engine, 'Z', false)), "ZA");
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn a_held_key_stops_holding_back_the_keys_after_it() {
+        let (mut engine, clock) = manual_engine();
+        key(&mut engine, 'Z' as u32, true, clock.now());
+        clock.advance(Duration::from_millis(20));
+        key(&mut engine, 'X' as u32, true, clock.now());
+        clock.advance(Duration::from_millis(20));
+        key(&mut engine, 'X' as u32, false, clock.now());
+        let mut actions = Vec::new();
+        for _ in 0..200 {
+            clock.advance(Duration::from_millis(1));
+            actions.extend(engine.update());
+        }
+        assert_eq!(typed(&actions), "X");
+        let up = key(&mut engine, 'Z' as u32, false, clock.now());
+        assert_eq!(typed(&up.actions), "Z");
+    }
+
+    #[test]
+    fn activation_tap_threshold_is_exact() {
+        for (held_ms, expect_tap) in [(199, true), (201, false)] {
+            let (mut engine, clock) = manual_engine();
+            key(&mut engine, 'A' as u32, true, clock.now());
+            clock.advance(Duration::from_millis(held_ms));
+            let up = key(&mut engine, 'A' as u32, false, clock.now());
+            assert_eq!(up.actions.is_empty(), !expect_tap, "held {}ms", held_ms);
+            assert_eq!(up.propagate, !expect_tap, "held {}ms", held_ms);
+        }
+    }
+
+    #[test]
+    fn reload_waits_for_the_active_mode_to_deactivate() {
+        let mut engine = default_engine();
+        let t0 = Instant::now();
+        key(&mut engine, 'A' as u32, true, t0);
+        assert_eq!(engine.current_mode_name(), Some("num_mode"));
+        engine.reload_modes(Vec::new());
+        // num_mode still owns A and types through its mapping
+        key(
+            &mut engine,
+            'D' as u32,
+            true,
+            t0 + Duration::from_millis(250),
+        );
+        let up = key(
+            &mut engine,
+            'D' as u32,
+            false,
+            t0 + Duration::from_millis(300),
+        );
+        assert_eq!(
+            up.actions,
+            vec![Action::KeyTap {
+                vk_code: '3' as u32,
+                modifiers: vec![]
+            }]
+        );
+        key(
+            &mut engine,
+            'A' as u32,
+            false,
+            t0 + Duration::from_millis(400),
+        );
+        assert_eq!(engine.current_mode_name(), None);
+        // with no modes left, A no longer activates anything
+        key(
+            &mut engine,
+            'A' as u32,
+            true,
+            t0 + Duration::from_millis(500),
+        );
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn tap_timeout_is_per_mode() {
+        let mut engine = Engine::new(modes_with(|cfg| {
+            if cfg.name == "num_mode" {
+                cfg.tap_timeout_ms = Some(300);
+            }
+        }));
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(1);
+        // num_mode waits 300ms, symbols keeps the 200ms default
+        for (vk_code, down_at) in [('A' as u32, t0), ('S' as u32, t1)] {
+            let expect_tap = vk_code == 'A' as u32;
+            key(&mut engine, vk_code, true, down_at);
+            let up = key(
+                &mut engine,
+                vk_code,
+                false,
+                down_at + Duration::from_millis(250),
+            );
+            assert_eq!(up.actions.is_empty(), !expect_tap, "{:#X}", vk_code);
+        }
+    }
+
+    #[test]
+    fn repeated_key_released_after_its_mode_types_nothing() {
+        let (mut engine, clock) = manual_engine();
+        // J activates vim arrow keys, L repeats DOWN
+        key(&mut engine, 'J' as u32, true, clock.now());
+        key(&mut engine, 'L' as u32, true, clock.now());
+        let mut repeats = Vec::new();
+        for _ in 0..400 {
+            clock.advance(Duration::from_millis(1));
+            repeats.extend(engine.update());
+        }
+        // first repeat at 300ms, then every 50ms
+        assert_eq!(repeats.len(), 3, "{:?}", repeats);
+        assert!(repeats.iter().all(|action| *action
+            == Action::KeyTap {
+                vk_code: 0x28,
+                modifiers: vec![]
+            }));
+        let release = key(&mut engine, 'J' as u32, false, clock.now());
+        assert!(release.actions.is_empty());
+        assert_eq!(engine.current_mode_name(), None);
+        clock.advance(Duration::from_millis(100));
+        assert!(engine.update().is_empty());
+        let up = key(&mut engine, 'L' as u32, false, clock.now());
+        assert!(up.actions.is_empty(), "{:?}", up.actions);
+    }
+
+    #[test]
+    fn modes_stack_and_fall_through() {
+        let (mut engine, clock) = manual_engine();
+        let step = |engine: &mut Engine, vk_code: char, is_key_down: bool| {
+            clock.advance(Duration::from_millis(20));
+            key(engine, vk_code as u32, is_key_down, clock.now())
+        };
+        // A holds num_mode; Q isn't mapped there, so it stacks the CTRL mode on top
+        step(&mut engine, 'A', true);
+        step(&mut engine, 'Q', true);
+        assert_eq!(engine.active_mode_names(), vec!["num_mode", "CTRL"]);
+        step(&mut engine, 'C', true);
+        let copy = step(&mut engine, 'C', false);
+        assert_eq!(
+            copy.actions,
+            vec![Action::KeyTap {
+                vk_code: 'C' as u32,
+                modifiers: vec![0x11]
+            }]
+        );
+        // CTRL maps nothing, so D falls through to num_mode
+        step(&mut engine, 'D', true);
+        let three = step(&mut engine, 'D', false);
+        assert_eq!(
+            three.actions,
+            vec![Action::KeyTap {
+                vk_code: '3' as u32,
+                modifiers: vec![]
+            }]
+        );
+        // releasing the bottom layer first leaves CTRL active until Q goes up
+        step(&mut engine, 'A', false);
+        assert_eq!(engine.active_mode_names(), vec!["CTRL"]);
+        step(&mut engine, 'Q', false);
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn quick_tap_of_a_stacked_mode_is_typed_by_the_layer_below() {
+        let (mut engine, clock) = manual_engine();
+        key(&mut engine, 'Q' as u32, true, clock.now());
+        clock.advance(Duration::from_millis(20));
+        // A would stack num_mode, but a quick tap types A through CTRL
+        key(&mut engine, 'A' as u32, true, clock.now());
+        assert_eq!(engine.active_mode_names(), vec!["CTRL", "num_mode"]);
+        clock.advance(Duration::from_millis(50));
+        let up = key(&mut engine, 'A' as u32, false, clock.now());
+        assert_eq!(
+            up.actions,
+            vec![Action::KeyTap {
+                vk_code: 'A' as u32,
+                modifiers: vec![0x11]
+            }]
+        );
+        assert_eq!(engine.active_mode_names(), vec!["CTRL"]);
+    }
+
+    /// The default modes plus "chorded", activated by D and F together, mapping J to LEFT.
+    fn chord_engine() -> (Engine, Arc<ManualClock>) {
+        let (mut engine, clock) = manual_engine();
+        let chorded = serde_json::from_str(
+            r#"{"name": "chorded", "activation_chord": ["D", "F"], "auto_modifiers": [],
+                "key_mapping": {"J": {"key": "LEFT"}}}"#,
+        )
+        .unwrap();
+        engine
+            .available_modes
+            .insert(0, Box::new(BasicMode::new(chorded)));
+        (engine, clock)
+    }
+
+    fn at(
+        engine: &mut Engine,
+        vk_code: char,
+        is_key_down: bool,
+        clock: &ManualClock,
+        ms: u64,
+    ) -> Decision {
+        clock.advance(Duration::from_millis(ms));
+        key(engine, vk_code as u32, is_key_down, clock.now())
+    }
+
+    #[test]
+    fn chord_activates_its_mode() {
+        let (mut engine, clock) = chord_engine();
+        assert!(!at(&mut engine, 'F', true, &clock, 0).propagate);
+        assert_eq!(engine.current_mode_name(), None);
+        at(&mut engine, 'D', true, &clock, 30);
+        assert_eq!(engine.active_mode_names(), vec!["chorded"]);
+        at(&mut engine, 'J', true, &clock, 100);
+        let left = at(&mut engine, 'J', false, &clock, 50);
+        assert_eq!(
+            left.actions,
+            vec![Action::KeyTap {
+                vk_code: 0x25,
+                modifiers: vec![]
+            }]
+        );
+        // either chord key ends the mode; the other then releases silently
+        let end = at(&mut engine, 'D', false, &clock, 50);
+        assert!(end.actions.is_empty());
+        assert_eq!(engine.current_mode_name(), None);
+        assert!(at(&mut engine, 'F', false, &clock, 20).actions.is_empty());
+    }
+
+    #[test]
+    fn unused_chord_tapped_quickly_types_its_keys_in_order() {
+        let (mut engine, clock) = chord_engine();
+        at(&mut engine, 'D', true, &clock, 0);
+        at(&mut engine, 'F', true, &clock, 20);
+        let up = at(&mut engine, 'F', false, &clock, 40);
+        assert_eq!(
+            up.actions,
+            vec![
+                Action::KeyTap {
+                    vk_code: 'D' as u32,
+                    modifiers: vec![]
+                },
+                Action::KeyTap {
+                    vk_code: 'F' as u32,
+                    modifiers: vec![]
+                },
+            ]
+        );
+        assert!(at(&mut engine, 'D', false, &clock, 10).actions.is_empty());
+    }
+
+    #[test]
+    fn incomplete_chord_is_typed_normally() {
+        let (mut engine, clock) = chord_engine();
+        // released before F arrives: just a D
+        at(&mut engine, 'D', true, &clock, 0);
+        let up = at(&mut engine, 'D', false, &clock, 30);
+        assert_eq!(
+            up.actions,
+            vec![Action::KeyTap {
+                vk_code: 'D' as u32,
+                modifiers: vec![]
+            }]
+        );
+        // F comes too late: the window expires in update() and D activates its own mode
+        at(&mut engine, 'D', true, &clock, 500);
+        assert_eq!(engine.current_mode_name(), None);
+        for _ in 0..50 {
+            clock.advance(Duration::from_millis(1));
+            engine.update();
+        }
+        assert_eq!(engine.current_mode_name(), Some("delimeter"));
+        at(&mut engine, 'F', true, &clock, 20);
+        let paren = at(&mut engine, 'F', false, &clock, 20);
+        assert_eq!(
+            paren.actions,
+            vec![Action::KeyTap {
+                vk_code: '9' as u32,
+                modifiers: vec![0x10]
+            }]
+        );
+    }
+
+    /// The default modes with `name` switched to `style`, leaving on ESC when locked.
+    fn styled_engine(name: &str, style: &str) -> (Engine, Arc<ManualClock>) {
+        let (mut engine, clock) = manual_engine();
+        engine.available_modes = modes_with(|cfg| {
+            if cfg.name == name {
+                cfg.activation_style = serde_json::from_str(style).unwrap();
+                cfg.exit_key = Some("ESC".to_string());
+            }
+        });
+        (engine, clock)
+    }
+
+    fn tap_key(engine: &mut Engine, vk_code: u32, clock: &ManualClock) -> Vec<Action> {
+        clock.advance(Duration::from_millis(100));
+        let mut actions = key(engine, vk_code, true, clock.now()).actions;
+        clock.advance(Duration::from_millis(50));
+        actions.extend(key(engine, vk_code, false, clock.now()).actions);
+        actions
+    }
+
+    #[test]
+    fn toggle_mode_locks_on_a_tap_and_leaves_on_the_next() {
+        let (mut engine, clock) = styled_engine("num_mode", r#""toggle""#);
+        assert!(tap_key(&mut engine, 'A' as u32, &clock).is_empty());
+        assert_eq!(engine.current_mode_name(), Some("num_mode"));
+        assert_eq!(
+            tap_key(&mut engine, 'D' as u32, &clock),
+            vec![Action::KeyTap {
+                vk_code: '3' as u32,
+                modifiers: vec![]
+            }]
+        );
+        assert!(tap_key(&mut engine, 'A' as u32, &clock).is_empty());
+        assert_eq!(engine.current_mode_name(), None);
+        // holding the key still works momentarily
+        key(&mut engine, 'A' as u32, true, clock.now());
+        clock.advance(Duration::from_millis(300));
+        key(&mut engine, 'A' as u32, false, clock.now());
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn double_tap_locks_and_exit_key_leaves() {
+        let (mut engine, clock) = styled_engine("num_mode", r#""double_tap_lock""#);
+        let a = vec![Action::KeyTap {
+            vk_code: 'A' as u32,
+            modifiers: vec![],
+        }];
+        assert_eq!(tap_key(&mut engine, 'A' as u32, &clock), a);
+        assert_eq!(engine.current_mode_name(), None);
+        assert!(tap_key(&mut engine, 'A' as u32, &clock).is_empty());
+        assert_eq!(engine.current_mode_name(), Some("num_mode"));
+        assert!(tap_key(&mut engine, 0x1B, &clock).is_empty());
+        assert_eq!(engine.current_mode_name(), None);
+        // too slow for a double tap
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tap_key(&mut engine, 'A' as u32, &clock), a);
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(tap_key(&mut engine, 'A' as u32, &clock), a);
+        assert_eq!(engine.current_mode_name(), None);
+    }
+
+    #[test]
+    fn one_shot_mode_applies_to_the_next_key_only() {
+        let (mut engine, clock) = styled_engine("num_mode", r#""one_shot""#);
+        assert!(tap_key(&mut engine, 'A' as u32, &clock).is_empty());
+        assert_eq!(
+            tap_key(&mut engine, 'D' as u32, &clock),
+            vec![Action::KeyTap {
+                vk_code: '3' as u32,
+                modifiers: vec![]
+            }]
+        );
+        assert_eq!(engine.current_mode_name(), None);
+        // D is delimeter's activation key; a quick tap types it
+        assert_eq!(
+            tap_key(&mut engine, 'D' as u32, &clock),
+            vec![Action::KeyTap {
+                vk_code: 'D' as u32,
+                modifiers: vec![]
+            }]
+        );
+    }
+
+    #[test]
+    fn one_shot_modifier_times_out() {
+        // the CTRL mode on Q and P only adds CTRL
+        let (mut engine, clock) = styled_engine("CTRL", r#""one_shot""#);
+        assert!(tap_key(&mut engine, 'Q' as u32, &clock).is_empty());
+        assert_eq!(
+            tap_key(&mut engine, 'S' as u32, &clock),
+            vec![Action::KeyTap {
+                vk_code: 'S' as u32,
+                modifiers: vec![0x11]
+            }]
+        );
+        assert!(tap_key(&mut engine, 'Q' as u32, &clock).is_empty());
+        for _ in 0..1000 {
+            clock.advance(Duration::from_millis(1));
+            engine.update();
+        }
+        assert_eq!(engine.current_mode_name(), None);
+        assert_eq!(
+            tap_key(&mut engine, 'X' as u32, &clock),
+            vec![Action::KeyTap {
+                vk_code: 'X' as u32,
+                modifiers: vec![]
+            }]
+        );
+    }
+
+    /// Press and release keys at the given times (ms from the start) with num_mode using `strategy`.
+    fn tap_hold_trace(strategy: &str, trace: &[(char, bool, u64)]) -> Vec<Action> {
+        run_trace(
+            |cfg| {
+                if cfg.name == "num_mode" {
+                    cfg.tap_hold = serde_json::from_str(strategy).unwrap();
+                }
+            },
+            trace,
+        )
+    }
+
+    /// Run a trace of `(key, down, at_ms)` through the default modes as changed by
+    /// `configure`, updating every millisecond in between.
+    fn run_trace(configure: impl Fn(&mut ModeConfig), trace: &[(char, bool, u64)]) -> Vec<Action> {
+        let (mut engine, clock) = manual_engine();
+        engine.available_modes = modes_with(configure);
+        let start = clock.now();
+        let mut actions = Vec::new();
+        for (vk_code, is_key_down, at_ms) in trace {
+            while clock.now() < start + Duration::from_millis(*at_ms) {
+                clock.advance(Duration::from_millis(1));
+                actions.extend(engine.update());
+            }
+            actions.extend(key(&mut engine, *vk_code as u32, *is_key_down, clock.now()).actions);
+        }
+        actions
+    }
+
+    fn typed(actions: &[Action]) -> String {
+        actions
+            .iter()
+            .map(|action| match action {
+                Action::KeyTap { vk_code, .. } => char::from_u32(*vk_code).unwrap(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tap_hold_strategies_resolve_rolls_and_nested_taps() {
+        // A activates num_mode, where S types 2
+        let roll = [
+            ('A', true, 0),
+            ('S', true, 30),
+            ('A', false, 60),
+            ('S', false, 90),
+        ];
+        let nested = [
+            ('A', true, 0),
+            ('S', true, 30),
+            ('S', false, 60),
+            ('A', false, 90),
+        ];
+        let slow_nested = [
+            ('A', true, 0),
+            ('S', true, 30),
+            ('S', false, 60),
+            ('A', false, 150),
+        ];
+        let cases = [
+            (r#""timeout""#, "AS", "2", "2"),
+            (r#""hold_on_other_key_press""#, "2", "2", "2"),
+            (r#""permissive_hold""#, "AS", "2", "2"),
+            (r#""balanced""#, "AS", "AS", "2"),
+        ];
+        for (strategy, rolled, nested_tap, slow_nested_tap) in cases {
+            assert_eq!(
+                typed(&tap_hold_trace(strategy, &roll)),
+                rolled,
+                "{} roll",
+                strategy
+            );
+            assert_eq!(
+                typed(&tap_hold_trace(strategy, &nested)),
+                nested_tap,
+                "{} nested",
+                strategy
+            );
+            assert_eq!(
+                typed(&tap_hold_trace(strategy, &slow_nested)),
+                slow_nested_tap,
+                "{} slow nested",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn held_past_the_tap_timeout_is_a_hold_for_every_strategy() {
+        let trace = [
+            ('A', true, 0),
+            ('S', true, 250),
+            ('S', false, 280),
+            ('A', false, 300),
+        ];
+        for strategy in [
+            r#""timeout""#,
+            r#""hold_on_other_key_press""#,
+            r#""permissive_hold""#,
+            r#""balanced""#,
+        ] {
+            assert_eq!(
+                typed(&tap_hold_trace(strategy, &trace)),
+                "2",
+                "{}",
+                strategy
+            );
+        }
+        // a quick lone tap is still the key itself
+        let tap = [('A', true, 0), ('A', false, 50)];
+        assert_eq!(typed(&tap_hold_trace(r#""balanced""#, &tap)), "A");
+    }
+
+    fn tap_dance_trace(trace: &[(char, bool, u64)]) -> String {
+        // In symbols (held S), E types 1 once, 2 twice and 3 when held
+        let actions = run_trace(
+            |cfg| {
+                if cfg.name == "symbols" {
+                    cfg.key_mapping.insert(
+                        "E".to_string(),
+                        serde_json::from_str(
+                            r#"{"tap_dance": [
+                                {"taps": 1, "key": "1"},
+                                {"taps": 2, "key": "2"},
+                                {"taps": 1, "hold": true, "key": "3"}
+                            ]}"#,
+                        )
+                        .unwrap(),
+                    );
+                }
+            },
+            trace,
+        );
+        typed(&actions)
+    }
+
+    #[test]
+    fn tap_dance_picks_the_output_by_tap_count() {
+        let single = [
+            ('S', true, 0),
+            ('E', true, 300),
+            ('E', false, 320),
+            ('S', false, 1000),
+        ];
+        assert_eq!(tap_dance_trace(&single), "1");
+        let double = [
+            ('S', true, 0),
+            ('E', true, 300),
+            ('E', false, 320),
+            ('E', true, 400),
+            ('E', false, 420),
+            ('S', false, 1000),
+        ];
+        assert_eq!(tap_dance_trace(&double), "2");
+        let held = [
+            ('S', true, 0),
+            ('E', true, 300),
+            ('E', false, 700),
+            ('S', false, 1000),
+        ];
+        assert_eq!(tap_dance_trace(&held), "3");
+    }
+
+    #[test]
+    fn usage_is_counted_until_taken() {
+        let (mut engine, clock) = manual_engine();
+        // A held for num_mode's 2, then tapped to type itself.
+        at(&mut engine, 'A', true, &clock, 0);
+        at(&mut engine, 'S', true, &clock, 40);
+        at(&mut engine, 'S', false, &clock, 20);
+        at(&mut engine, 'A', false, &clock, 300);
+        at(&mut engine, 'A', true, &clock, 500);
+        at(&mut engine, 'A', false, &clock, 50);
+
+        let stats = engine.take_stats();
+        assert_eq!(stats.key_down_events, 3);
+        // Everything but the release of the held A.
+        assert_eq!(stats.blocked_events, 5);
+        let num_mode = &stats.modes["num_mode"];
+        assert_eq!(
+            (num_mode.activations, num_mode.taps, num_mode.holds),
+            (2, 1, 1)
+        );
+        assert_eq!(num_mode.mappings.get("S"), Some(&1));
+        assert_eq!(num_mode.keys.get("S"), Some(&1));
+        assert_eq!(stats.keys.get("A"), Some(&2));
+        assert!(engine.take_stats().is_empty());
+    }
+
+    #[test]
+    fn auto_repeat_counts_as_one_press() {
+        let (mut engine, clock) = manual_engine();
+        // Backspace is passed through, Q is typed on release; both auto-repeat.
+        for vk_code in [0x08, 'Q' as u32] {
+            for _ in 0..5 {
+                clock.advance(Duration::from_millis(30));
+                key(&mut engine, vk_code, true, clock.now());
+            }
+            key(&mut engine, vk_code, false, clock.now());
+        }
+        clock.advance(Duration::from_millis(30));
+        key(&mut engine, 0x08, true, clock.now());
+
+        let stats = engine.take_stats();
+        assert_eq!(stats.key_down_events, 11);
+        assert_eq!(stats.keys.get("BACKSPACE"), Some(&2));
+        assert_eq!(stats.keys.get("Q"), Some(&1));
+    }
+
+    fn require_idle_trace(trace: &[(char, bool, u64)]) -> String {
+        typed(&run_trace(
+            |cfg| {
+                if cfg.name == "num_mode" {
+                    cfg.require_idle_ms = Some(150);
+                }
+            },
+            trace,
+        ))
+    }
+
+    #[test]
+    fn activation_keys_type_themselves_while_typing() {
+        // A activates num_mode, where S types 2
+        let typing = [
+            ('X', true, 0),
+            ('X', false, 40),
+            ('A', true, 100),
+            ('S', true, 130),
+            ('S', false, 160),
+            ('A', false, 200),
+        ];
+        assert_eq!(require_idle_trace(&typing), "XAS");
+        let after_a_pause = [
+            ('X', true, 0),
+            ('X', false, 40),
+            ('A', true, 400),
+            ('S', true, 450),
+            ('S', false, 480),
+            ('A', false, 520),
+        ];
+        assert_eq!(require_idle_trace(&after_a_pause), "X2");
+        // A tapped activation key is a keystroke too.
+        let tapped_twice = [
+            ('A', true, 0),
+            ('A', false, 50),
+            ('A', true, 120),
+            ('S', true, 150),
+            ('S', false, 170),
+            ('A', false, 200),
+        ];
+        assert_eq!(require_idle_trace(&tapped_twice), "AAS");
+    }
+
+    /// "terminal" and "ide" both on D, told apart by the focused application,
+    /// and "arrows" on F everywhere but in a game.
+    fn app_engine() -> (Engine, Arc<ManualClock>, Arc<FakeContext>) {
+        let modes_config: ModesConfig = serde_json::from_str(
+            r#"{"modes": [
+                {"name": "terminal", "activation_keys": ["D"],
+                 "app_match": {"process_name": "WindowsTerminal.exe"},
+                 "key_mapping": {"J": {"text": "ls"}}},
+                {"name": "ide", "activation_keys": ["D"],
+                 "app_match": {"process_name": "code.exe", "title": "\\.rs\\b"},
+                 "key_mapping": {"J": {"text": "fn"}}},
+                {"name": "arrows", "activation_keys": ["F"],
+                 "app_match": {"window_class": "UnityWndClass", "exclude": true},
+                 "key_mapping": {"J": {"key": "LEFT"}}}
+            ]}"#,
+        )
+        .unwrap();
+        let modes = modes_config
+            .modes
+            .into_iter()
+            .map(|cfg| Box::new(BasicMode::new(cfg)) as Box<dyn Mode + Send>)
+            .collect();
+        let clock = Arc::new(ManualClock::new());
+        let mut engine = Engine::with_clock(modes, clock.clone());
+        let context = Arc::new(FakeContext::default());
+        engine.set_context_provider(context.clone());
+        (engine, clock, context)
+    }
+
+    fn focus(context: &FakeContext, process_name: &str, window_class: &str, title: &str) {
+        context.set(Some(AppContext {
+            process_name: process_name.to_string(),
+            window_class: window_class.to_string(),
+            title: title.to_string(),
+        }));
+    }
+
+    #[test]
+    fn modes_follow_the_focused_application() {
+        let (mut engine, clock, context) = app_engine();
+        let hold_d_tap_j = |engine: &mut Engine| {
+            let mut actions = at(engine, 'D', true, &clock, 100).actions;
+            let names: Vec<String> = engine
+                .active_mode_names()
+                .into_iter()
+                .map(str::to_string)
+                .collect();
+            actions.extend(at(engine, 'J', true, &clock, 10).actions);
+            actions.extend(at(engine, 'J', false, &clock, 10).actions);
+            actions.extend(at(engine, 'D', false, &clock, 10).actions);
+            (names, actions)
+        };
+
+        focus(
+            &context,
+            "WindowsTerminal.exe",
+            "CASCADIA_HOSTING_WINDOW_CLASS",
+            "pwsh",
+        );
+        let (names, actions) = hold_d_tap_j(&mut engine);
+        assert_eq!(names, vec!["terminal"]);
+        assert_eq!(actions, vec![Action::Text("ls".to_string())]);
+
+        focus(
+            &context,
+            "Code.exe",
+            "Chrome_WidgetWin_1",
+            "engine.rs - crate",
+        );
+        let (names, actions) = hold_d_tap_j(&mut engine);
+        assert_eq!(names, vec!["ide"]);
+        assert_eq!(actions, vec![Action::Text("fn".to_string())]);
+
+        // Neither matches a Markdown file, nor an application that can't be told.
+        focus(
+            &context,
+            "Code.exe",
+            "Chrome_WidgetWin_1",
+            "README.md - crate",
+        );
+        assert!(hold_d_tap_j(&mut engine).0.is_empty());
+        context.set(None);
+        assert!(hold_d_tap_j(&mut engine).0.is_empty());
+    }
+
+    #[test]
+    fn excluded_applications_disable_a_mode() {
+        let (mut engine, clock, context) = app_engine();
+        focus(&context, "game.exe", "UnityWndClass", "Game");
+        at(&mut engine, 'F', true, &clock, 100);
+        assert!(engine.active_mode_names().is_empty());
+        assert_eq!(
+            at(&mut engine, 'F', false, &clock, 10).actions,
+            vec![Action::KeyTap {
+                vk_code: 'F' as u32,
+                modifiers: vec![]
+            }]
+        );
+
+        focus(
+            &context,
+            "Code.exe",
+            "Chrome_WidgetWin_1",
+            "engine.rs - crate",
+        );
+        at(&mut engine, 'F', true, &clock, 100);
+        assert_eq!(engine.active_mode_names(), vec!["arrows"]);
+    }
+
+    #[test]
+    fn the_focused_application_is_only_asked_for_by_filtered_modes() {
+        let (mut engine, clock, context) = app_engine();
+        let queries = || context.queries.load(std::sync::atomic::Ordering::Relaxed);
+        // Plain keys and auto-repeat never ask.
+        for (key, down) in [
+            ('Q', true),
+            ('Q', false),
+            ('X', true),
+            ('X', true),
+            ('X', false),
+        ] {
+            at(&mut engine, key, down, &clock, 100);
+        }
+        assert_eq!(queries(), 0);
+        // Two modes on D, but the press asks once; its repeats don't ask again.
+        at(&mut engine, 'D', true, &clock, 100);
+        at(&mut engine, 'D', true, &clock, 30);
+        assert_eq!(queries(), 1);
+    }
+
+    #[test]
+    fn tap_dance_ends_on_another_key_or_when_its_mode_does() {
+        let other_key = [
+            ('S', true, 0),
+            ('E', true, 300),
+            ('E', false, 320),
+            ('E', true, 340),
+            ('E', false, 360),
+            ('E', true, 380),
+            ('Z', true, 390),
+            ('Z', false, 400),
+            ('E', false, 410),
+            ('S', false, 1000),
+        ];
+        // Two taps end the dance right away; the third is held when Z cuts it short.
+        assert_eq!(tap_dance_trace(&other_key), "23Z");
+        let mode_released = [
+            ('S', true, 0),
+            ('E', true, 300),
+            ('E', false, 320),
+            ('S', false, 330),
+        ];
+        assert_eq!(tap_dance_trace(&mode_released), "1");
+    }
+
+    #[test]
+    fn modes_can_be_locked_on_request_and_suspended() {
+        let (mut engine, clock) = manual_engine();
+        engine.activate_mode("num_mode").unwrap();
+        assert_eq!(
+            tap_key(&mut engine, 'D' as u32, &clock),
+            vec![Action::KeyTap {
+                vk_code: '3' as u32,
+                modifiers: vec![]
+            }]
+        );
+        // Its activation key leaves it like a locked mode.
+        assert!(tap_key(&mut engine, 'A' as u32, &clock).is_empty());
+        assert_eq!(engine.current_mode_name(), None);
+
+        engine.activate_mode("num_mode").unwrap();
+        engine.suspend();
+        assert_eq!(engine.current_mode_name(), None);
+        assert!(engine.activate_mode("num_mode").is_err());
+        let down = key(&mut engine, 'A' as u32, true, clock.now());
+        assert!(down.propagate && down.actions.is_empty());
+        assert!(key(&mut engine, 'A' as u32, false, clock.now()).propagate);
+        assert_eq!(engine.current_mode_name(), None);
+
+        engine.resume();
+        key(&mut engine, 'A' as u32, true, clock.now());
+        assert_eq!(engine.current_mode_name(), Some("num_mode"));
+    }
+
+    #[test]
+    fn suspending_sends_decided_output_and_releases_what_is_held() {
+        let engine = || {
+            // Loading would refuse the unreleased LCTRL; a mapping cut off between its
+            // down and up steps leaves the same state behind.
+            let config: ModeConfig = serde_json::from_str(
+                r#"{"name": "hold_ctrl", "activation_keys": ["F"], "auto_modifiers": [],
+                    "key_mapping": {"D": {"steps": [{"down": "LCTRL"}]}}}"#,
+            )
+            .unwrap();
+            let modes: Vec<Box<dyn Mode + Send>> = vec![
+                Box::new(BasicMode::new(config)),
+                Box::new(crate::mouse_mode::MouseMode::from_config(
+                    crate::mouse_mode::default_mouse_config(),
+                )),
+            ];
+            let clock = Arc::new(ManualClock::new());
+            (Engine::with_clock(modes, clock.clone()), clock)
+        };
+        let press = |engine: &mut Engine, clock: &ManualClock, key: u32, down: bool| {
+            clock.advance(Duration::from_millis(20));
+            engine.process(KeyEvent::new(key, down, clock.now()));
+        };
+
+        // X is typed once Z is, which is still undecided.
+        let (mut queued, clock) = engine();
+        press(&mut queued, &clock, 'Z' as u32, true);
+        press(&mut queued, &clock, 'X' as u32, true);
+        press(&mut queued, &clock, 'X' as u32, false);
+        assert_eq!(
+            queued.suspend(),
+            vec![Action::KeyTap {
+                vk_code: 'X' as u32,
+                modifiers: vec![]
+            }]
+        );
+
+        let (mut stepped, clock) = engine();
+        press(&mut stepped, &clock, 'F' as u32, true);
+        press(&mut stepped, &clock, 'D' as u32, true);
+        press(&mut stepped, &clock, 'D' as u32, false);
+        assert_eq!(stepped.suspend(), vec![Action::KeyUp(0xA2)]);
+
+        let (mut clicking, clock) = engine();
+        press(&mut clicking, &clock, 0x20, true);
+        press(&mut clicking, &clock, 'Q' as u32, true);
+        assert_eq!(
+            clicking.suspend(),
+            vec![Action::MouseButton {
+                button: MouseButton::Left,
+                down: false
+            }]
+        );
+        assert!(clicking.update().is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+// foreground_window.rs
+//! The focused application on Windows: the foreground window's title and class
+//! and the file name of the process that owns it.
+use windows::core::PWSTR;
+use windows::Win32::Foundation::{CloseHandle, HWND};
+use windows::Win32::System::Threading::{
+    OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetClassNameW, GetForegroundWindow, GetWindowTextW, GetWindowThreadProcessId,
+};
+
+use crate::app_context::{AppContext, ContextProvider};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForegroundWindow;
+
+impl ContextProvider for ForegroundWindow {
+    fn current(&self) -> Option<AppContext> {
+        let hwnd = unsafe { GetForegroundWindow() };
+        if hwnd.is_invalid() {
+            return None;
+        }
+        let mut title = [0u16; 512];
+        let title_len = unsafe { GetWindowTextW(hwnd, &mut title) };
+        let mut class = [0u16; 256];
+        let class_len = unsafe { GetClassNameW(hwnd, &mut class) };
+        Some(AppContext {
+            process_name: process_name(hwnd).unwrap_or_default(),
+            window_class: String::from_utf16_lossy(&class[..class_len.max(0) as usize]),
+            title: String::from_utf16_lossy(&title[..title_len.max(0) as usize]),
+        })
+    }
+}
+
+/// The file name of the executable that owns `hwnd`, e.g. `Code.exe`.
+fn process_name(hwnd: HWND) -> Option<String> {
+    let mut process_id = 0u32;
+    unsafe { GetWindowThreadProcessId(hwnd, Some(&mut process_id as *mut u32)) };
+    if process_id == 0 {
+        return None;
+    }
+    let process =
+        unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id) }.ok()?;
+    let mut path = [0u16; 1024];
+    let mut len = path.len() as u32;
+    let result = unsafe {
+        QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(path.as_mut_ptr()),
+            &mut len,
+        )
+    };
+    let _ = unsafe { CloseHandle(process) };
+    result.ok()?;
+    let path = String::from_utf16_lossy(&path[..len as usize]);
+    path.rsplit('\\').next().map(str::to_string)
+}
@@ -0,0 +1,300 @@
+// heatmap.rs
+//! `bushido_keys_rs heatmap`: how often each key was pressed in each mode, and in no
+//! mode, drawn on a keyboard as SVG and listed as CSV.
+//!
+//! The counts come from `usage_stats.json`. Each mode gets its own keyboard, shaded
+//! from white for unused keys to red for the most used key in that mode.
+use crate::mode_loader;
+use crate::stats::{key_name, UsageStats};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+pub const USAGE: &str =
+    "usage: bushido_keys_rs heatmap [--stats usage_stats.json] [--out directory]";
+
+const NO_MODE: &str = "(no mode)";
+
+/// A US keyboard, row by row: virtual key code, label and width in key units.
+const ROWS: &[&[(u32, &str, f64)]] = &[
+    &[
+        (0xC0, "`", 1.0),
+        (0x31, "1", 1.0),
+        (0x32, "2", 1.0),
+        (0x33, "3", 1.0),
+        (0x34, "4", 1.0),
+        (0x35, "5", 1.0),
+        (0x36, "6", 1.0),
+        (0x37, "7", 1.0),
+        (0x38, "8", 1.0),
+        (0x39, "9", 1.0),
+        (0x30, "0", 1.0),
+        (0xBD, "-", 1.0),
+        (0xBB, "=", 1.0),
+        (0x08, "Backspace", 2.0),
+    ],
+    &[
+        (0x09, "Tab", 1.5),
+        (0x51, "Q", 1.0),
+        (0x57, "W", 1.0),
+        (0x45, "E", 1.0),
+        (0x52, "R", 1.0),
+        (0x54, "T", 1.0),
+        (0x59, "Y", 1.0),
+        (0x55, "U", 1.0),
+        (0x49, "I", 1.0),
+        (0x4F, "O", 1.0),
+        (0x50, "P", 1.0),
+        (0xDB, "[", 1.0),
+        (0xDD, "]", 1.0),
+        (0xDC, "\\", 1.5),
+    ],
+    &[
+        (0x14, "Caps", 1.75),
+        (0x41, "A", 1.0),
+        (0x53, "S", 1.0),
+        (0x44, "D", 1.0),
+        (0x46, "F", 1.0),
+        (0x47, "G", 1.0),
+        (0x48, "H", 1.0),
+        (0x4A, "J", 1.0),
+        (0x4B, "K", 1.0),
+        (0x4C, "L", 1.0),
+        (0xBA, ";", 1.0),
+        (0xDE, "'", 1.0),
+        (0x0D, "Enter", 2.25),
+    ],
+    &[
+        (0xA0, "Shift", 2.25),
+        (0x5A, "Z", 1.0),
+        (0x58, "X", 1.0),
+        (0x43, "C", 1.0),
+        (0x56, "V", 1.0),
+        (0x42, "B", 1.0),
+        (0x4E, "N", 1.0),
+        (0x4D, "M", 1.0),
+        (0xBC, ",", 1.0),
+        (0xBE, ".", 1.0),
+        (0xBF, "/", 1.0),
+        (0xA1, "Shift", 2.75),
+    ],
+    &[
+        (0xA2, "Ctrl", 1.25),
+        (0x5B, "Win", 1.25),
+        (0xA4, "Alt", 1.25),
+        (0x20, "Space", 6.25),
+        (0xA5, "Alt", 1.25),
+        (0x5C, "Win", 1.25),
+        (0x5D, "Menu", 1.25),
+        (0xA3, "Ctrl", 1.25),
+    ],
+];
+
+/// Pixels per key unit, gap included.
+const UNIT: f64 = 40.0;
+const GAP: f64 = 4.0;
+/// Room above each keyboard for its mode's name.
+const TITLE_HEIGHT: f64 = 30.0;
+
+/// Entry point for `bushido_keys_rs heatmap ...`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut stats_path = None;
+    let mut out_dir = PathBuf::from(".");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stats" => stats_path = Some(PathBuf::from(args.next().ok_or(USAGE)?)),
+            "--out" => out_dir = PathBuf::from(args.next().ok_or(USAGE)?),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let stats_path = match stats_path {
+        Some(path) => path,
+        None => mode_loader::config_dir()?.join("usage_stats.json"),
+    };
+    let stats: UsageStats = mode_loader::read_json(&stats_path)?;
+    let counts = key_counts(&stats);
+    for (name, contents) in [
+        ("heatmap.svg", to_svg(&counts)),
+        ("heatmap.csv", to_csv(&counts)),
+    ] {
+        let path = out_dir.join(name);
+        fs::write(&path, contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// The key counts to show: no mode first, then every mode in which a key was pressed.
+fn key_counts(stats: &UsageStats) -> Vec<(&str, &BTreeMap<String, u64>)> {
+    let modes = stats
+        .modes
+        .iter()
+        .filter(|(_, mode)| !mode.keys.is_empty())
+        .map(|(name, mode)| (name.as_str(), &mode.keys));
+    std::iter::once((NO_MODE, &stats.keys))
+        .chain(modes)
+        .collect()
+}
+
+/// One row per key, keyboard keys first, and one column per mode.
+fn to_csv(counts: &[(&str, &BTreeMap<String, u64>)]) -> String {
+    let mut keys: Vec<String> = Vec::new();
+    let layout_keys = ROWS.iter().flat_map(|row| row.iter());
+    for name in layout_keys.map(|(vk_code, _, _)| key_name(*vk_code)) {
+        if !keys.contains(&name) {
+            keys.push(name);
+        }
+    }
+    let mut others: Vec<&String> = counts
+        .iter()
+        .flat_map(|(_, keys)| keys.keys())
+        .filter(|name| !keys.contains(name))
+        .collect();
+    others.sort();
+    others.dedup();
+    keys.extend(others.into_iter().cloned());
+
+    let header = std::iter::once("key").chain(counts.iter().map(|(mode, _)| *mode));
+    let mut csv = csv_line(header);
+    for key in keys.iter() {
+        let values: Vec<String> = counts
+            .iter()
+            .map(|(_, keys)| keys.get(key).copied().unwrap_or(0).to_string())
+            .collect();
+        csv.push_str(&csv_line(
+            std::iter::once(key.as_str()).chain(values.iter().map(String::as_str)),
+        ));
+    }
+    csv
+}
+
+fn csv_line<'a>(fields: impl Iterator<Item = &'a str>) -> String {
+    let fields: Vec<String> = fields
+        .map(|field| {
+            if field.contains([',', '"', '\n']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    format!("{}\n", fields.join(","))
+}
+
+/// A keyboard per mode, one below the other.
+fn to_svg(counts: &[(&str, &BTreeMap<String, u64>)]) -> String {
+    let width = 15.0 * UNIT + GAP;
+    let keyboard_height = TITLE_HEIGHT + ROWS.len() as f64 * UNIT + GAP;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" font-family=\"sans-serif\">\n",
+        width,
+        keyboard_height * counts.len() as f64
+    );
+    for (index, (mode, keys)) in counts.iter().enumerate() {
+        let top = index as f64 * keyboard_height;
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" font-size=\"16\">{}</text>\n",
+            GAP,
+            top + TITLE_HEIGHT - 10.0,
+            escape_xml(mode)
+        ));
+        let count_of = |vk_code: u32| keys.get(&key_name(vk_code)).copied().unwrap_or(0);
+        let max = ROWS
+            .iter()
+            .flat_map(|row| row.iter())
+            .map(|(vk_code, _, _)| count_of(*vk_code))
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        for (row_index, row) in ROWS.iter().enumerate() {
+            let y = top + TITLE_HEIGHT + row_index as f64 * UNIT;
+            let mut x = GAP;
+            for (vk_code, label, units) in row.iter() {
+                let count = count_of(*vk_code);
+                svg.push_str(&format!(
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"{}\" stroke=\"#999\"/>\n",
+                    x,
+                    y,
+                    units * UNIT - GAP,
+                    UNIT - GAP,
+                    heat_color(count as f64 / max as f64)
+                ));
+                svg.push_str(&format!(
+                    "<text x=\"{}\" y=\"{}\" font-size=\"11\">{}</text>\n",
+                    x + 4.0,
+                    y + 13.0,
+                    escape_xml(label)
+                ));
+                if count > 0 {
+                    svg.push_str(&format!(
+                        "<text x=\"{}\" y=\"{}\" font-size=\"9\">{}</text>\n",
+                        x + 4.0,
+                        y + UNIT - GAP - 5.0,
+                        count
+                    ));
+                }
+                x += units * UNIT;
+            }
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// White for unused keys to red for the most used one.
+fn heat_color(heat: f64) -> String {
+    let (cold, hot) = ([255.0, 255.0, 255.0], [215.0, 48.0, 31.0]);
+    let channel = |i: usize| (cold[i] + (hot[i] - cold[i]) * heat).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(0), channel(1), channel(2))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> UsageStats {
+        let mut stats = UsageStats::default();
+        stats.keys.insert("A".to_string(), 4);
+        stats.keys.insert(",".to_string(), 1);
+        let mode = stats.mode("vim <arrows>");
+        mode.keys.insert("J".to_string(), 2);
+        mode.keys.insert("F13".to_string(), 1);
+        // Entered but no key pressed in it: no keyboard of its own.
+        stats.mode("unused").activations = 1;
+        stats
+    }
+
+    #[test]
+    fn csv_has_a_column_per_mode_and_a_row_per_key() {
+        let stats = stats();
+        let csv = to_csv(&key_counts(&stats));
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "key,(no mode),vim <arrows>");
+        assert!(lines.contains(&"A,4,0"));
+        assert!(lines.contains(&"J,0,2"));
+        assert!(lines.contains(&"\",\",1,0"));
+        // Keys off the drawn keyboard come last.
+        assert_eq!(lines.last(), Some(&"F13,0,1"));
+    }
+
+    #[test]
+    fn svg_draws_a_shaded_keyboard_per_mode() {
+        let stats = stats();
+        let svg = to_svg(&key_counts(&stats));
+        let keys_per_keyboard: usize = ROWS.iter().map(|row| row.len()).sum();
+        assert_eq!(svg.matches("<rect").count(), 2 * keys_per_keyboard);
+        assert!(svg.contains(">vim &lt;arrows&gt;</text>"));
+        assert!(!svg.contains("unused"));
+        // The most used key of each keyboard is the hottest.
+        assert_eq!(svg.matches(&heat_color(1.0)).count(), 2);
+        assert_eq!(heat_color(0.0), "#ffffff");
+    }
+}
@@ -1,169 +1,186 @@
 // src/input_simulator.rs
+#[cfg(windows)]
+use crate::input_sink::{Action, InputSink, MouseButton};
+#[cfg(windows)]
 use log::info;
+#[cfg(windows)]
 use windows::Win32::UI::Input::KeyboardAndMouse::*;
 
-pub fn move_mouse(dx: i32, dy: i32) {
-    // get screen width and height
+/// The Win32 sink: every call becomes a SendInput, and a batch becomes as few as its delays allow.
+#[cfg(windows)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendInputSink;
+
+#[cfg(windows)]
+fn mouse_input(dx: i32, dy: i32, mouse_data: i32, flags: MOUSE_EVENT_FLAGS) -> INPUT {
     let mouse_input = MOUSEINPUT {
         dx,
         dy,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_MOVE,
+        mouseData: mouse_data as u32, // WHEEL_DELTA is 120
+        dwFlags: flags,
         time: 0,
         dwExtraInfo: 0,
     };
-    let input = INPUT {
+    INPUT {
         r#type: INPUT_MOUSE,
         Anonymous: INPUT_0 { mi: mouse_input },
-    };
-    unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
-    }
-}
-pub fn simulate_scroll(dx: i32, dy: i32) {
-    // Handle vertical scrolling
-    if dy != 0 {
-        let mouse_input = MOUSEINPUT {
-            dx: 0,
-            dy: 0,
-            mouseData: (dy) as u32, // WHEEL_DELTA is 120
-            dwFlags: MOUSEEVENTF_WHEEL,
-            time: 0,
-            dwExtraInfo: 0,
-        };
-        let input = INPUT {
-            r#type: INPUT_MOUSE,
-            Anonymous: INPUT_0 { mi: mouse_input },
-        };
-        unsafe {
-            SendInput(&[input], size_of::<INPUT>() as i32);
-        }
-    }
-    // Handle horizontal scrolling
-    if dx != 0 {
-        let mouse_input = MOUSEINPUT {
-            dx: 0,
-            dy: 0,
-            mouseData: (dx) as u32, // WHEEL_DELTA is 120
-            dwFlags: MOUSEEVENTF_HWHEEL,
-            time: 0,
-            dwExtraInfo: 0,
-        };
-        let input = INPUT {
-            r#type: INPUT_MOUSE,
-            Anonymous: INPUT_0 { mi: mouse_input },
-        };
-        unsafe {
-            SendInput(&[input], size_of::<INPUT>() as i32);
-        }
     }
 }
 
-pub fn simulate_left_down() {
-    let mouse_input = MOUSEINPUT {
-        dx: 0,
-        dy: 0,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_LEFTDOWN,
+#[cfg(windows)]
+fn keyboard_input(vk_code: u32, flags: KEYBD_EVENT_FLAGS) -> INPUT {
+    let kb = KEYBDINPUT {
+        wVk: VIRTUAL_KEY(vk_code as u16),
+        wScan: 0,
+        dwFlags: flags,
         time: 0,
         dwExtraInfo: 0,
     };
-    let input = INPUT {
-        r#type: INPUT_MOUSE,
-        Anonymous: INPUT_0 { mi: mouse_input },
-    };
-    unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 { ki: kb },
     }
 }
-// add simulate_left_up, simulate_right_down/up, simulate_middle_down/up.
-pub fn simulate_left_up() {
-    let mouse_input = MOUSEINPUT {
-        dx: 0,
-        dy: 0,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_LEFTUP,
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    let input = INPUT {
-        r#type: INPUT_MOUSE,
-        Anonymous: INPUT_0 { mi: mouse_input },
-    };
+
+#[cfg(windows)]
+fn send(inputs: &[INPUT]) {
     unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
+        SendInput(inputs, size_of::<INPUT>() as i32);
     }
 }
 
-pub fn simulate_right_down() {
-    let mouse_input = MOUSEINPUT {
-        dx: 0,
-        dy: 0,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_RIGHTDOWN,
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    let input = INPUT {
-        r#type: INPUT_MOUSE,
-        Anonymous: INPUT_0 { mi: mouse_input },
-    };
-    unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
+#[cfg(windows)]
+fn tap_inputs(vk_code: u32, modifiers: &[u32]) -> Vec<INPUT> {
+    let mut inputs = Vec::new();
+    // Press modifier keys
+    for mod_vk in modifiers.iter() {
+        inputs.push(keyboard_input(*mod_vk, KEYBD_EVENT_FLAGS(0)));
+    }
+    // Main key down and up
+    inputs.push(keyboard_input(vk_code, KEYBD_EVENT_FLAGS(0)));
+    inputs.push(keyboard_input(vk_code, KEYEVENTF_KEYUP));
+    // Release modifier keys in reverse order
+    for mod_vk in modifiers.iter().rev() {
+        inputs.push(keyboard_input(*mod_vk, KEYEVENTF_KEYUP));
     }
+    inputs
 }
 
-pub fn simulate_right_up() {
-    let mouse_input = MOUSEINPUT {
-        dx: 0,
-        dy: 0,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_RIGHTUP,
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    let input = INPUT {
-        r#type: INPUT_MOUSE,
-        Anonymous: INPUT_0 { mi: mouse_input },
-    };
-    unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
+/// KEYEVENTF_UNICODE presses for every UTF-16 unit, independent of the keyboard layout.
+#[cfg(windows)]
+fn text_inputs(text: &str) -> Vec<INPUT> {
+    let mut inputs = Vec::new();
+    for unit in text.encode_utf16() {
+        for flags in [KEYEVENTF_UNICODE, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP] {
+            let kb = KEYBDINPUT {
+                wVk: VIRTUAL_KEY(0),
+                wScan: unit,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            };
+            inputs.push(INPUT {
+                r#type: INPUT_KEYBOARD,
+                Anonymous: INPUT_0 { ki: kb },
+            });
+        }
     }
+    inputs
 }
 
-pub fn simulate_middle_down() {
-    let mouse_input = MOUSEINPUT {
-        dx: 0,
-        dy: 0,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_MIDDLEDOWN,
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    let input = INPUT {
-        r#type: INPUT_MOUSE,
-        Anonymous: INPUT_0 { mi: mouse_input },
-    };
-    unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
+#[cfg(windows)]
+fn scroll_inputs(dx: i32, dy: i32) -> Vec<INPUT> {
+    let mut inputs = Vec::new();
+    // Handle vertical scrolling
+    if dy != 0 {
+        inputs.push(mouse_input(0, 0, dy, MOUSEEVENTF_WHEEL));
+    }
+    // Handle horizontal scrolling
+    if dx != 0 {
+        inputs.push(mouse_input(0, 0, dx, MOUSEEVENTF_HWHEEL));
     }
+    inputs
 }
 
-pub fn simulate_middle_up() {
-    let mouse_input = MOUSEINPUT {
-        dx: 0,
-        dy: 0,
-        mouseData: 0,
-        dwFlags: MOUSEEVENTF_MIDDLEUP,
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    let input = INPUT {
-        r#type: INPUT_MOUSE,
-        Anonymous: INPUT_0 { mi: mouse_input },
-    };
-    unsafe {
-        SendInput(&[input], size_of::<INPUT>() as i32);
+#[cfg(windows)]
+fn mouse_button_input(button: MouseButton, down: bool) -> INPUT {
+    let flags = match (button, down) {
+        (MouseButton::Left, true) => MOUSEEVENTF_LEFTDOWN,
+        (MouseButton::Left, false) => MOUSEEVENTF_LEFTUP,
+        (MouseButton::Right, true) => MOUSEEVENTF_RIGHTDOWN,
+        (MouseButton::Right, false) => MOUSEEVENTF_RIGHTUP,
+        (MouseButton::Middle, true) => MOUSEEVENTF_MIDDLEDOWN,
+        (MouseButton::Middle, false) => MOUSEEVENTF_MIDDLEUP,
+    };
+    mouse_input(0, 0, 0, flags)
+}
+
+#[cfg(windows)]
+impl InputSink for SendInputSink {
+    fn key_down(&mut self, vk_code: u32) {
+        send(&[keyboard_input(vk_code, KEYBD_EVENT_FLAGS(0))]);
+    }
+    fn key_up(&mut self, vk_code: u32) {
+        send(&[keyboard_input(vk_code, KEYEVENTF_KEYUP)]);
+    }
+    /// Sends the whole tap as one SendInput batch so nothing can interleave with it.
+    fn key_tap(&mut self, vk_code: u32, modifiers: &[u32]) {
+        info!(
+            "input_simulator.rs: simulating key tap, main vk_code translates to: {}",
+            get_char_from_vk_code(vk_code)
+        );
+        send(&tap_inputs(vk_code, modifiers));
+    }
+    fn move_mouse(&mut self, dx: i32, dy: i32) {
+        send(&[mouse_input(dx, dy, 0, MOUSEEVENTF_MOVE)]);
+    }
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        let inputs = scroll_inputs(dx, dy);
+        if !inputs.is_empty() {
+            send(&inputs);
+        }
+    }
+    fn mouse_button(&mut self, button: MouseButton, down: bool) {
+        send(&[mouse_button_input(button, down)]);
+    }
+    fn text(&mut self, text: &str) {
+        let inputs = text_inputs(text);
+        if !inputs.is_empty() {
+            send(&inputs);
+        }
+    }
+    /// Everything between delays goes out in a single SendInput.
+    fn send_batch(&mut self, actions: &[Action]) {
+        let mut inputs = Vec::new();
+        for action in actions.iter() {
+            match action {
+                Action::KeyDown(vk_code) => {
+                    inputs.push(keyboard_input(*vk_code, KEYBD_EVENT_FLAGS(0)))
+                }
+                Action::KeyUp(vk_code) => inputs.push(keyboard_input(*vk_code, KEYEVENTF_KEYUP)),
+                Action::KeyTap { vk_code, modifiers } => {
+                    inputs.extend(tap_inputs(*vk_code, modifiers))
+                }
+                Action::MouseMove { dx, dy } => {
+                    inputs.push(mouse_input(*dx, *dy, 0, MOUSEEVENTF_MOVE))
+                }
+                Action::Scroll { dx, dy } => inputs.extend(scroll_inputs(*dx, *dy)),
+                Action::MouseButton { button, down } => {
+                    inputs.push(mouse_button_input(*button, *down))
+                }
+                Action::Text(text) => inputs.extend(text_inputs(text)),
+                Action::Delay(duration) => {
+                    if !inputs.is_empty() {
+                        send(&inputs);
+                        inputs.clear();
+                    }
+                    self.delay(*duration);
+                }
+            }
+        }
+        if !inputs.is_empty() {
+            send(&inputs);
+        }
     }
 }
 
@@ -190,73 +207,3 @@ pub fn get_char_from_vk_code(vk_code: u32) -> char {
         _ => char::from_u32(vk_code).unwrap_or('?'),
     }
 }
-
-/// Simulates a key tap with optional modifier keys.
-pub fn simulate_key_tap(vk_code: u32, modifiers: &[u32], auto_modifiers: &[u32]) {
-    let modifiers: Vec<u32> = modifiers
-        .iter()
-        .chain(auto_modifiers.iter())
-        .copied()
-        .collect();
-    let char = get_char_from_vk_code(vk_code);
-    info!(
-        "input_simulator.rs: top of simulating key tap function, main vk_code translates to: {}",
-        char
-    );
-    let mut inputs = Vec::new();
-    // Press modifier keys
-    for mod_vk in modifiers.iter() {
-        let kb = KEYBDINPUT {
-            wVk: VIRTUAL_KEY(*mod_vk as u16),
-            wScan: 0,
-            dwFlags: KEYBD_EVENT_FLAGS(0),
-            time: 0,
-            dwExtraInfo: 0,
-        };
-        inputs.push(INPUT {
-            r#type: INPUT_KEYBOARD,
-            Anonymous: INPUT_0 { ki: kb },
-        });
-    }
-    // Main key down
-    let kb_down = KEYBDINPUT {
-        wVk: VIRTUAL_KEY(vk_code as u16),
-        wScan: 0,
-        dwFlags: KEYBD_EVENT_FLAGS(0),
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    inputs.push(INPUT {
-        r#type: INPUT_KEYBOARD,
-        Anonymous: INPUT_0 { ki: kb_down },
-    });
-    // Main key up
-    let kb_up = KEYBDINPUT {
-        wVk: VIRTUAL_KEY(vk_code as u16),
-        wScan: 0,
-        dwFlags: KEYEVENTF_KEYUP,
-        time: 0,
-        dwExtraInfo: 0,
-    };
-    inputs.push(INPUT {
-        r#type: INPUT_KEYBOARD,
-        Anonymous: INPUT_0 { ki: kb_up },
-    });
-    // Release modifier keys in reverse order
-    for mod_vk in modifiers.iter().rev() {
-        let kb = KEYBDINPUT {
-            wVk: VIRTUAL_KEY(*mod_vk as u16),
-            wScan: 0,
-            dwFlags: KEYEVENTF_KEYUP,
-            time: 0,
-            dwExtraInfo: 0,
-        };
-        inputs.push(INPUT {
-            r#type: INPUT_KEYBOARD,
-            Anonymous: INPUT_0 { ki: kb },
-        });
-    }
-    unsafe {
-        SendInput(&inputs, size_of::<INPUT>() as i32);
-    }
-}
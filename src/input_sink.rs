@@ -0,0 +1,196 @@
+// input_sink.rs
+//! The output side of bushido keys. Modes describe what to synthesize through an
+//! `InputSink`; each backend provides the sink that actually injects the input.
+use crate::conversion::char_to_key_tap;
+use log::warn;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+/// A single piece of synthesized input, as recorded by `RecordingSink`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    KeyDown(u32),
+    KeyUp(u32),
+    /// Press the modifiers in order, tap the key, then release the modifiers in reverse.
+    KeyTap {
+        vk_code: u32,
+        modifiers: Vec<u32>,
+    },
+    MouseMove {
+        dx: i32,
+        dy: i32,
+    },
+    Scroll {
+        dx: i32,
+        dy: i32,
+    },
+    MouseButton {
+        button: MouseButton,
+        down: bool,
+    },
+    /// A pause inside a batch, e.g. between the steps of a mapping.
+    Delay(Duration),
+    /// Text typed as characters, whatever the keyboard layout.
+    Text(String),
+}
+
+pub trait InputSink {
+    fn key_down(&mut self, vk_code: u32);
+    fn key_up(&mut self, vk_code: u32);
+    /// Tap a key while holding the given modifiers.
+    fn key_tap(&mut self, vk_code: u32, modifiers: &[u32]) {
+        for modifier in modifiers.iter() {
+            self.key_down(*modifier);
+        }
+        self.key_down(vk_code);
+        self.key_up(vk_code);
+        for modifier in modifiers.iter().rev() {
+            self.key_up(*modifier);
+        }
+    }
+    /// Move the mouse relative to its current position.
+    fn move_mouse(&mut self, dx: i32, dy: i32);
+    /// Scroll the wheel; positive dy scrolls up, positive dx scrolls right.
+    fn scroll(&mut self, dx: i32, dy: i32);
+    fn mouse_button(&mut self, button: MouseButton, down: bool);
+    fn delay(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+    /// Type arbitrary text. Sinks that can inject characters directly override this;
+    /// the fallback taps US-layout keys and skips characters it has no key for.
+    fn text(&mut self, text: &str) {
+        for c in text.chars() {
+            match char_to_key_tap(c) {
+                Some((vk_code, true)) => self.key_tap(vk_code, &[0x10]),
+                Some((vk_code, false)) => self.key_tap(vk_code, &[]),
+                None => warn!("No key types {:?} on this backend", c),
+            }
+        }
+    }
+
+    /// Replay a recorded action into this sink.
+    fn send(&mut self, action: &Action) {
+        match action {
+            Action::KeyDown(vk_code) => self.key_down(*vk_code),
+            Action::KeyUp(vk_code) => self.key_up(*vk_code),
+            Action::KeyTap { vk_code, modifiers } => self.key_tap(*vk_code, modifiers),
+            Action::MouseMove { dx, dy } => self.move_mouse(*dx, *dy),
+            Action::Scroll { dx, dy } => self.scroll(*dx, *dy),
+            Action::MouseButton { button, down } => self.mouse_button(*button, *down),
+            Action::Delay(duration) => self.delay(*duration),
+            Action::Text(text) => self.text(text),
+        }
+    }
+
+    /// Send a decision's actions as one batch. Sinks that can inject several
+    /// events at once override this so nothing interleaves with the batch.
+    fn send_batch(&mut self, actions: &[Action]) {
+        for action in actions.iter() {
+            self.send(action);
+        }
+    }
+}
+
+/// Tap a key with the mapping's modifiers followed by the mode's auto modifiers.
+pub fn key_tap_with_auto_modifiers(
+    sink: &mut dyn InputSink,
+    vk_code: u32,
+    modifiers: &[u32],
+    auto_modifiers: &[u32],
+) {
+    let modifiers: Vec<u32> = modifiers
+        .iter()
+        .chain(auto_modifiers.iter())
+        .copied()
+        .collect();
+    sink.key_tap(vk_code, &modifiers);
+}
+
+/// An in-memory sink that records everything sent to it.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    pub actions: Vec<Action>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl InputSink for RecordingSink {
+    fn key_down(&mut self, vk_code: u32) {
+        self.actions.push(Action::KeyDown(vk_code));
+    }
+    fn key_up(&mut self, vk_code: u32) {
+        self.actions.push(Action::KeyUp(vk_code));
+    }
+    fn key_tap(&mut self, vk_code: u32, modifiers: &[u32]) {
+        self.actions.push(Action::KeyTap {
+            vk_code,
+            modifiers: modifiers.to_vec(),
+        });
+    }
+    fn move_mouse(&mut self, dx: i32, dy: i32) {
+        self.actions.push(Action::MouseMove { dx, dy });
+    }
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        self.actions.push(Action::Scroll { dx, dy });
+    }
+    fn mouse_button(&mut self, button: MouseButton, down: bool) {
+        self.actions.push(Action::MouseButton { button, down });
+    }
+    fn delay(&mut self, duration: Duration) {
+        self.actions.push(Action::Delay(duration));
+    }
+    fn text(&mut self, text: &str) {
+        self.actions.push(Action::Text(text.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records only key downs and ups, so the default methods are exercised.
+    #[derive(Default)]
+    struct KeySink {
+        actions: Vec<Action>,
+    }
+
+    impl InputSink for KeySink {
+        fn key_down(&mut self, vk_code: u32) {
+            self.actions.push(Action::KeyDown(vk_code));
+        }
+        fn key_up(&mut self, vk_code: u32) {
+            self.actions.push(Action::KeyUp(vk_code));
+        }
+        fn move_mouse(&mut self, _dx: i32, _dy: i32) {}
+        fn scroll(&mut self, _dx: i32, _dy: i32) {}
+        fn mouse_button(&mut self, _button: MouseButton, _down: bool) {}
+    }
+
+    #[test]
+    fn text_falls_back_to_us_layout_taps() {
+        let mut sink = KeySink::default();
+        sink.text("a(λ");
+        assert_eq!(
+            sink.actions,
+            vec![
+                Action::KeyDown('A' as u32),
+                Action::KeyUp('A' as u32),
+                Action::KeyDown(0x10),
+                Action::KeyDown('9' as u32),
+                Action::KeyUp('9' as u32),
+                Action::KeyUp(0x10),
+            ]
+        );
+    }
+}
@@ -1,63 +1,26 @@
-use lazy_static::lazy_static;
-use once_cell::sync::Lazy;
 use std::collections::HashMap;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
-};
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct KeyState {
     pub vk_code: i32,
     pub name: String,
     pub time_pressed: Instant,
-    pub timeout: u32,
     pub held: bool,
     pub prev_held: bool,
     pub time_released: Instant,
-    pub was_double_tap: bool,
+    /// How many times in a row this key went down with no other key pressed in between.
+    pub tap_count: u32,
     pub was_shift_held_on_key_down: bool,
-}
-
-impl Default for KeyState {
-    fn default() -> Self {
-        Self {
-            vk_code: 0,
-            name: "no name".to_string(),
-            time_pressed: Instant::now(),
-            timeout: 200,
-            held: false,
-            prev_held: false,
-            time_released: Instant::now(),
-            was_double_tap: false,
-            was_shift_held_on_key_down: false,
-        }
-    }
-}
-
-pub type SafeKeyState = Arc<Mutex<KeyState>>;
-
-pub static KEY_STATES: Lazy<Mutex<HashMap<i32, SafeKeyState>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
-
-pub static DEBUG_ENABLED: AtomicBool = AtomicBool::new(false);
-
-pub fn set_debug(enabled: bool) {
-    DEBUG_ENABLED.store(enabled, Ordering::SeqCst);
-}
-
-pub fn is_debug_enabled() -> bool {
-    DEBUG_ENABLED.load(Ordering::Relaxed)
-}
-
-#[macro_export]
-macro_rules! debug_println {
-    ($($arg:tt)*) => {
-        if $crate::key_state::is_debug_enabled() {
-            println!($($arg)*);
-        }
-    }
+    /// This press already produced its output, by auto-repeat or by completing
+    /// an activation chord, so its release types nothing.
+    pub consumed: bool,
+    /// The activation key of the mode layer that handled the key going down.
+    pub layer: Option<u32>,
+    /// When the previous press of this key went down and came back up.
+    pub previous_press: Option<(Instant, Instant)>,
+    /// This press is the key a one-shot mode was waiting for; its release turns the mode off.
+    pub ends_one_shot: bool,
 }
 
 // Virtual key codes for modifier keys
@@ -72,17 +35,21 @@ pub const VK_LALT: u32 = 0xA4;
 pub const VK_RALT: u32 = 0xA5;
 
 impl KeyState {
-    pub fn new(vk_code: i32) -> Self {
+    /// A key that has not been pressed yet, with both timestamps set to `now`.
+    pub fn new(vk_code: i32, now: Instant) -> Self {
         Self {
             vk_code,
-            time_pressed: Instant::now(),
-            time_released: Instant::now(),
+            time_pressed: now,
+            time_released: now,
             held: false,
             name: "no name".to_string(),
-            timeout: 200,
             prev_held: false,
-            was_double_tap: false,
+            tap_count: 0,
             was_shift_held_on_key_down: false,
+            consumed: false,
+            layer: None,
+            previous_press: None,
+            ends_one_shot: false,
         }
     }
 
@@ -105,9 +72,8 @@ impl KeyState {
 }
 
 /// Get the current state of all modifier keys
-pub fn get_active_modifiers() -> Vec<u32> {
+pub fn get_active_modifiers(states: &HashMap<i32, KeyState>) -> Vec<u32> {
     let mut modifiers = Vec::new();
-    let states = KEY_STATES.lock().unwrap();
 
     // Check each modifier key
     for &vk_code in &[
@@ -122,7 +88,7 @@ pub fn get_active_modifiers() -> Vec<u32> {
         VK_RALT as i32,
     ] {
         if let Some(state) = states.get(&vk_code) {
-            if state.lock().unwrap().held {
+            if state.held {
                 modifiers.push(vk_code as u32);
             }
         }
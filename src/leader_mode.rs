@@ -0,0 +1,370 @@
+// leader_mode.rs
+//! A mode entered by tapping its activation key, which then waits for a short key
+//! sequence such as `G S` and types the output mapped to it.
+use crate::app_context::AppFilter;
+use crate::conversion::string_to_vk;
+use crate::input_sink::InputSink;
+use crate::key_state::KeyState;
+use crate::mapping::Mapping;
+use crate::mode::{Activation, Mode};
+use crate::mode_config::{ActivationStyle, ModeConfig};
+use log::info;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Sequences by their keys, one level per key.
+#[derive(Debug, Clone, Default)]
+struct SequenceTrie {
+    output: Option<Mapping>,
+    next: HashMap<u32, SequenceTrie>,
+}
+
+impl SequenceTrie {
+    fn insert(&mut self, keys: &[u32], output: Mapping) {
+        match keys.split_first() {
+            None => self.output = Some(output),
+            Some((key, rest)) => self.next.entry(*key).or_default().insert(rest, output),
+        }
+    }
+
+    fn get(&self, keys: &[u32]) -> Option<&SequenceTrie> {
+        match keys.split_first() {
+            None => Some(self),
+            Some((key, rest)) => self.next.get(key)?.get(rest),
+        }
+    }
+}
+
+/// Split a sequence such as `"G S"` into its keys.
+pub fn sequence_keys(sequence: &str) -> impl Iterator<Item = &str> {
+    sequence.split_whitespace()
+}
+
+#[derive(Debug, Clone)]
+pub struct LeaderMode {
+    pub config: ModeConfig,
+    activation_keys: Vec<u32>,
+    activation_chord: Vec<u32>,
+    activation: Activation,
+    app_filter: Option<AppFilter>,
+    sequences: SequenceTrie,
+    auto_modifiers: Vec<u32>,
+    activated_by: Option<u32>,
+    locked_at: Option<Instant>,
+    was_mode_used: bool,
+    /// The keys typed so far, with whether shift was held.
+    typed: Vec<(u32, bool)>,
+    last_key_at: Option<Instant>,
+    finished: bool,
+    /// The sequence that was typed, for the usage statistics.
+    used_sequence: Option<String>,
+}
+
+impl LeaderMode {
+    /// Leader modes always lock on when their key is tapped; holding the key works too.
+    pub fn new(config: ModeConfig) -> Self {
+        let mut sequences = SequenceTrie::default();
+        for (sequence, entry) in config.sequences.iter() {
+            let keys: Vec<u32> = sequence_keys(sequence).map(string_to_vk).collect();
+            sequences.insert(&keys, Mapping::new(entry, None));
+        }
+        let activation = Activation {
+            style: ActivationStyle::Toggle,
+            exit_key: config.exit_key.as_deref().map(string_to_vk),
+            double_tap: config.get_double_tap(),
+            one_shot_timeout: config.get_one_shot_timeout(),
+            tap_hold: config.tap_hold,
+            balanced_min: config.get_balanced_min(),
+            require_idle: config.get_require_idle(),
+        };
+        Self {
+            activation_keys: config
+                .activation_keys
+                .iter()
+                .map(|s| string_to_vk(s))
+                .collect(),
+            activation_chord: config
+                .activation_chord
+                .iter()
+                .map(|s| string_to_vk(s))
+                .collect(),
+            activation,
+            app_filter: AppFilter::for_mode(&config),
+            sequences,
+            auto_modifiers: Vec::new(),
+            activated_by: None,
+            locked_at: None,
+            was_mode_used: false,
+            typed: Vec::new(),
+            last_key_at: None,
+            finished: false,
+            used_sequence: None,
+            config,
+        }
+    }
+
+    fn typed_keys(&self) -> Vec<u32> {
+        self.typed.iter().map(|(vk_code, _)| *vk_code).collect()
+    }
+
+    /// Type the output of the sequence typed so far, or give up on it, and leave the mode.
+    fn finish(&mut self, sink: &mut dyn InputSink) {
+        self.finished = true;
+        let keys = self.typed_keys();
+        if let Some(output) = self
+            .sequences
+            .get(&keys)
+            .and_then(|node| node.output.as_ref())
+        {
+            info!(
+                "LeaderMode ({}): sequence {:X?}",
+                self.config.get_name(),
+                keys
+            );
+            output.emit(false, &[], sink);
+            self.used_sequence = self
+                .config
+                .sequences
+                .keys()
+                .find(|sequence| {
+                    sequence_keys(sequence)
+                        .map(string_to_vk)
+                        .eq(keys.iter().copied())
+                })
+                .cloned();
+        } else if self.config.replay_unmatched {
+            info!(
+                "LeaderMode ({}): no sequence {:X?}, typing its keys",
+                self.config.get_name(),
+                keys
+            );
+            for (vk_code, shift) in self.typed.iter() {
+                let modifiers: &[u32] = if *shift { &[0x10] } else { &[] };
+                sink.key_tap(*vk_code, modifiers);
+            }
+        } else {
+            info!(
+                "LeaderMode ({}): no sequence {:X?}",
+                self.config.get_name(),
+                keys
+            );
+        }
+        self.typed.clear();
+    }
+}
+
+impl Mode for LeaderMode {
+    fn handle_key_down_event(
+        &mut self,
+        key_state: &mut KeyState,
+        sink: &mut dyn InputSink,
+    ) -> bool {
+        let vk_code = key_state.vk_code as u32;
+        // Shift only changes what a replayed key types.
+        if vk_code == 0x10 || self.finished {
+            return true;
+        }
+        self.was_mode_used = true;
+        self.typed
+            .push((vk_code, key_state.was_shift_held_on_key_down));
+        self.last_key_at = Some(key_state.time_pressed);
+        let keys = self.typed_keys();
+        match self.sequences.get(&keys) {
+            // Wait while a longer sequence may still follow.
+            Some(node) if !node.next.is_empty() => {}
+            _ => self.finish(sink),
+        }
+        true
+    }
+    fn handle_key_up_event(
+        &mut self,
+        _key_state: &mut KeyState,
+        _sink: &mut dyn InputSink,
+    ) -> bool {
+        true
+    }
+    fn update(&mut self, now: Instant, sink: &mut dyn InputSink) {
+        if self.finished {
+            return;
+        }
+        // The wait starts once the key is tapped; while it is held there is no hurry.
+        let Some(since) = self.last_key_at.or(self.locked_at) else {
+            return;
+        };
+        if now.duration_since(since) >= self.config.get_sequence_timeout() {
+            info!("LeaderMode ({}): timed out", self.config.get_name());
+            self.finish(sink);
+        }
+    }
+    fn get_name(&self) -> &str {
+        self.config.get_name()
+    }
+    fn get_activation_keys(&self) -> &Vec<u32> {
+        &self.activation_keys
+    }
+    fn get_activation_chord(&self) -> &[u32] {
+        &self.activation_chord
+    }
+    fn get_chord_window(&self) -> Duration {
+        self.config.get_chord_window()
+    }
+    fn get_activation(&self) -> &Activation {
+        &self.activation
+    }
+    fn get_locked_at(&self) -> Option<Instant> {
+        self.locked_at
+    }
+    fn set_locked_at(&mut self, locked_at: Option<Instant>) {
+        self.locked_at = locked_at;
+    }
+    /// Every key is part of the sequence, so no other mode stacks on top.
+    fn maps_key(&self, _vk_code: u32) -> bool {
+        true
+    }
+    fn get_tap_timeout(&self) -> Duration {
+        self.config.get_tap_timeout()
+    }
+    fn check_if_deactivates(&mut self, key_state: &mut KeyState) -> bool {
+        self.activated_by == Some(key_state.vk_code as u32)
+    }
+    fn clone_box(&self) -> Box<dyn Mode + Send> {
+        Box::new(self.clone())
+    }
+    fn set_activated_by(&mut self, key_code: u32) {
+        self.activated_by = Some(key_code);
+    }
+    fn get_activated_by(&self) -> Option<u32> {
+        self.activated_by
+    }
+    fn get_app_filter(&self) -> Option<&AppFilter> {
+        self.app_filter.as_ref()
+    }
+    fn get_auto_modifiers(&self) -> &Vec<u32> {
+        &self.auto_modifiers
+    }
+    fn was_mode_used(&self) -> bool {
+        self.was_mode_used
+    }
+    fn set_was_mode_used(&mut self, was_mode_used: bool) {
+        self.was_mode_used = was_mode_used;
+    }
+    fn take_repeated_keys(&mut self) -> Vec<u32> {
+        Vec::new()
+    }
+    fn finish_pending(&mut self, sink: &mut dyn InputSink) {
+        if !self.finished && !self.typed.is_empty() {
+            self.finish(sink);
+        }
+    }
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+    fn take_used_mappings(&mut self) -> Vec<String> {
+        self.used_sequence.take().into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::engine::{Engine, KeyEvent};
+    use crate::input_sink::Action;
+    use std::sync::Arc;
+
+    const CAPSLOCK: u32 = 0x14;
+
+    fn leader_engine(replay_unmatched: bool) -> (Engine, Arc<ManualClock>) {
+        let mut config: ModeConfig = serde_json::from_str(
+            r#"{
+                "name": "leader",
+                "activation_keys": ["CAPSLOCK"],
+                "sequences": {
+                    "G S": {"text": "git status"},
+                    "W": {"key": "W", "modifiers": ["CTRL"]},
+                    "W Q": {"key": "S", "modifiers": ["CTRL"]}
+                }
+            }"#,
+        )
+        .unwrap();
+        config.replay_unmatched = replay_unmatched;
+        let clock = Arc::new(ManualClock::new());
+        let engine = Engine::with_clock(vec![Box::new(LeaderMode::new(config))], clock.clone());
+        (engine, clock)
+    }
+
+    fn tap(engine: &mut Engine, clock: &ManualClock, keys: &[u32]) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for vk_code in keys {
+            for is_key_down in [true, false] {
+                clock.advance(Duration::from_millis(30));
+                let event = KeyEvent::new(*vk_code, is_key_down, clock.now());
+                actions.extend(engine.process(event).actions);
+            }
+        }
+        actions
+    }
+
+    fn wait(engine: &mut Engine, clock: &ManualClock, ms: u64) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for _ in 0..ms {
+            clock.advance(Duration::from_millis(1));
+            actions.extend(engine.update());
+        }
+        actions
+    }
+
+    #[test]
+    fn a_complete_sequence_types_its_output() {
+        let (mut engine, clock) = leader_engine(false);
+        assert!(tap(&mut engine, &clock, &[CAPSLOCK]).is_empty());
+        assert_eq!(engine.active_mode_names(), vec!["leader"]);
+        let actions = tap(&mut engine, &clock, &['G' as u32, 'S' as u32]);
+        assert_eq!(actions, vec![Action::Text("git status".to_string())]);
+        assert!(engine.active_mode_names().is_empty());
+        let stats = engine.take_stats();
+        assert_eq!(stats.modes["leader"].mappings.get("G S"), Some(&1));
+    }
+
+    #[test]
+    fn a_sequence_that_may_go_on_waits_for_the_timeout() {
+        let (mut engine, clock) = leader_engine(false);
+        tap(&mut engine, &clock, &[CAPSLOCK]);
+        assert!(tap(&mut engine, &clock, &['W' as u32]).is_empty());
+        assert_eq!(
+            wait(&mut engine, &clock, 1000),
+            vec![Action::KeyTap {
+                vk_code: 'W' as u32,
+                modifiers: vec![0x11]
+            }]
+        );
+        assert!(engine.active_mode_names().is_empty());
+    }
+
+    #[test]
+    fn an_unmatched_sequence_is_dropped_or_replayed() {
+        let (mut engine, clock) = leader_engine(false);
+        tap(&mut engine, &clock, &[CAPSLOCK]);
+        assert!(tap(&mut engine, &clock, &['G' as u32, 'X' as u32]).is_empty());
+        assert!(engine.active_mode_names().is_empty());
+
+        let (mut engine, clock) = leader_engine(true);
+        tap(&mut engine, &clock, &[CAPSLOCK]);
+        let replayed: Vec<u32> = tap(&mut engine, &clock, &['G' as u32, 'X' as u32])
+            .into_iter()
+            .map(|action| match action {
+                Action::KeyTap { vk_code, .. } => vk_code,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(replayed, vec!['G' as u32, 'X' as u32]);
+    }
+
+    #[test]
+    fn the_leader_gives_up_when_nothing_is_typed() {
+        let (mut engine, clock) = leader_engine(true);
+        tap(&mut engine, &clock, &[CAPSLOCK]);
+        assert!(wait(&mut engine, &clock, 1000).is_empty());
+        assert!(engine.active_mode_names().is_empty());
+    }
+}
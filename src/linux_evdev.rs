@@ -0,0 +1,586 @@
+// linux_evdev.rs
+//! The Linux backend: grab an evdev keyboard, feed its key events to the engine
+//! and re-emit everything through a uinput virtual device.
+//!
+//! The read loop works on any `Read`/`Write` pair of raw `input_event` records,
+//! so it can be driven from a pipe as well as from real devices.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::control;
+use crate::engine::{Engine, KeyEvent};
+use crate::input_sink::{Action, InputSink, MouseButton};
+use crate::ENGINE;
+
+// Event types and codes from linux/input-event-codes.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const SYN_REPORT: u16 = 0;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const REL_WHEEL_HI_RES: u16 = 0x0B;
+const REL_HWHEEL_HI_RES: u16 = 0x0C;
+/// Like Windows, the high resolution wheel axes count 120 per detent.
+const WHEEL_DELTA: i32 = 120;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+/// The highest key code; keys with no virtual key code go through unchanged, so the
+/// virtual device has to be able to send all of them.
+const KEY_MAX: u16 = 0x2FF;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTALT: u16 = 56;
+const BUS_USB: u16 = 0x03;
+
+// ioctl request numbers, see linux/input.h and linux/uinput.h
+const fn ioc(dir: u64, kind: u8, nr: u8, size: usize) -> u64 {
+    (dir << 30) | ((size as u64) << 16) | ((kind as u64) << 8) | nr as u64
+}
+const EVIOCGRAB: u64 = ioc(1, b'E', 0x90, mem::size_of::<libc::c_int>());
+const UI_DEV_CREATE: u64 = ioc(0, b'U', 1, 0);
+const UI_DEV_DESTROY: u64 = ioc(0, b'U', 2, 0);
+const UI_DEV_SETUP: u64 = ioc(1, b'U', 3, mem::size_of::<libc::uinput_setup>());
+const UI_SET_EVBIT: u64 = ioc(1, b'U', 100, mem::size_of::<libc::c_int>());
+const UI_SET_KEYBIT: u64 = ioc(1, b'U', 101, mem::size_of::<libc::c_int>());
+const UI_SET_RELBIT: u64 = ioc(1, b'U', 102, mem::size_of::<libc::c_int>());
+
+/// Linux key codes and the virtual key codes the modes are written against.
+const KEY_TABLE: &[(u16, u32)] = &[
+    (1, 0x1B),   // ESC
+    (2, 0x31),   // 1
+    (3, 0x32),   // 2
+    (4, 0x33),   // 3
+    (5, 0x34),   // 4
+    (6, 0x35),   // 5
+    (7, 0x36),   // 6
+    (8, 0x37),   // 7
+    (9, 0x38),   // 8
+    (10, 0x39),  // 9
+    (11, 0x30),  // 0
+    (12, 0xBD),  // MINUS
+    (13, 0xBB),  // EQUAL
+    (14, 0x08),  // BACKSPACE
+    (15, 0x09),  // TAB
+    (16, 0x51),  // Q
+    (17, 0x57),  // W
+    (18, 0x45),  // E
+    (19, 0x52),  // R
+    (20, 0x54),  // T
+    (21, 0x59),  // Y
+    (22, 0x55),  // U
+    (23, 0x49),  // I
+    (24, 0x4F),  // O
+    (25, 0x50),  // P
+    (26, 0xDB),  // LEFTBRACE
+    (27, 0xDD),  // RIGHTBRACE
+    (28, 0x0D),  // ENTER
+    (29, 0xA2),  // LEFTCTRL
+    (30, 0x41),  // A
+    (31, 0x53),  // S
+    (32, 0x44),  // D
+    (33, 0x46),  // F
+    (34, 0x47),  // G
+    (35, 0x48),  // H
+    (36, 0x4A),  // J
+    (37, 0x4B),  // K
+    (38, 0x4C),  // L
+    (39, 0xBA),  // SEMICOLON
+    (40, 0xDE),  // APOSTROPHE
+    (41, 0xC0),  // GRAVE
+    (42, 0xA0),  // LEFTSHIFT
+    (43, 0xDC),  // BACKSLASH
+    (44, 0x5A),  // Z
+    (45, 0x58),  // X
+    (46, 0x43),  // C
+    (47, 0x56),  // V
+    (48, 0x42),  // B
+    (49, 0x4E),  // N
+    (50, 0x4D),  // M
+    (51, 0xBC),  // COMMA
+    (52, 0xBE),  // DOT
+    (53, 0xBF),  // SLASH
+    (54, 0xA1),  // RIGHTSHIFT
+    (55, 0x6A),  // KPASTERISK
+    (56, 0xA4),  // LEFTALT
+    (57, 0x20),  // SPACE
+    (58, 0x14),  // CAPSLOCK
+    (59, 0x70),  // F1
+    (60, 0x71),  // F2
+    (61, 0x72),  // F3
+    (62, 0x73),  // F4
+    (63, 0x74),  // F5
+    (64, 0x75),  // F6
+    (65, 0x76),  // F7
+    (66, 0x77),  // F8
+    (67, 0x78),  // F9
+    (68, 0x79),  // F10
+    (69, 0x90),  // NUMLOCK
+    (70, 0x91),  // SCROLLLOCK
+    (71, 0x67),  // KP7
+    (72, 0x68),  // KP8
+    (73, 0x69),  // KP9
+    (74, 0x6D),  // KPMINUS
+    (75, 0x64),  // KP4
+    (76, 0x65),  // KP5
+    (77, 0x66),  // KP6
+    (78, 0x6B),  // KPPLUS
+    (79, 0x61),  // KP1
+    (80, 0x62),  // KP2
+    (81, 0x63),  // KP3
+    (82, 0x60),  // KP0
+    (83, 0x6E),  // KPDOT
+    (87, 0x7A),  // F11
+    (88, 0x7B),  // F12
+    (96, 0x0D),  // KPENTER
+    (97, 0xA3),  // RIGHTCTRL
+    (98, 0x6F),  // KPSLASH
+    (99, 0x2C),  // SYSRQ
+    (100, 0xA5), // RIGHTALT
+    (102, 0x24), // HOME
+    (103, 0x26), // UP
+    (104, 0x21), // PAGEUP
+    (105, 0x25), // LEFT
+    (106, 0x27), // RIGHT
+    (107, 0x23), // END
+    (108, 0x28), // DOWN
+    (109, 0x22), // PAGEDOWN
+    (110, 0x2D), // INSERT
+    (111, 0x2E), // DELETE
+    (113, 0xAD), // MUTE
+    (114, 0xAE), // VOLUMEDOWN
+    (115, 0xAF), // VOLUMEUP
+    (119, 0x13), // PAUSE
+    (125, 0x5B), // LEFTMETA
+    (126, 0x5C), // RIGHTMETA
+    (127, 0x5D), // COMPOSE
+];
+
+/// Translate a Linux key code into the virtual key code used by the modes.
+pub fn linux_to_vk(code: u16) -> Option<u32> {
+    KEY_TABLE
+        .iter()
+        .find(|(linux, _)| *linux == code)
+        .map(|(_, vk)| *vk)
+}
+
+/// Translate a virtual key code back into a Linux key code.
+pub fn vk_to_linux(vk_code: u32) -> Option<u16> {
+    // The generic modifiers are sent as their left-hand keys.
+    match vk_code {
+        0x10 => return Some(KEY_LEFTSHIFT),
+        0x11 => return Some(KEY_LEFTCTRL),
+        0x12 => return Some(KEY_LEFTALT),
+        _ => {}
+    }
+    KEY_TABLE
+        .iter()
+        .find(|(_, vk)| *vk == vk_code)
+        .map(|(linux, _)| *linux)
+}
+
+/// One `input_event` record without its timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl RawEvent {
+    pub fn new(kind: u16, code: u16, value: i32) -> Self {
+        Self { kind, code, value }
+    }
+
+    pub fn key(code: u16, value: i32) -> Self {
+        Self::new(EV_KEY, code, value)
+    }
+
+    fn syn() -> Self {
+        Self::new(EV_SYN, SYN_REPORT, 0)
+    }
+
+    /// Read one record; `Ok(None)` at end of input.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut buffer = [0u8; mem::size_of::<libc::input_event>()];
+        match reader.read_exact(&mut buffer) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let event: libc::input_event =
+            unsafe { std::ptr::read_unaligned(buffer.as_ptr() as *const libc::input_event) };
+        Ok(Some(Self::new(event.type_, event.code, event.value)))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = self.kind;
+        event.code = self.code;
+        event.value = self.value;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const libc::input_event as *const u8,
+                mem::size_of::<libc::input_event>(),
+            )
+        };
+        writer.write_all(bytes)
+    }
+}
+
+/// Writes synthesized input as raw events, e.g. into `/dev/uinput`.
+pub struct UinputSink<W: Write> {
+    writer: W,
+    /// Scroll not yet reported as whole detents on the classic wheel axes.
+    wheel_remainder_x: i32,
+    wheel_remainder_y: i32,
+}
+
+impl<W: Write> UinputSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            wheel_remainder_x: 0,
+            wheel_remainder_y: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write the events followed by a SYN_REPORT.
+    pub fn emit(&mut self, events: &[RawEvent]) {
+        let result = events
+            .iter()
+            .chain(std::iter::once(&RawEvent::syn()))
+            .try_for_each(|event| event.write_to(&mut self.writer))
+            .and_then(|_| self.writer.flush());
+        if let Err(e) = result {
+            log::error!("Failed to write to the virtual device: {}", e);
+        }
+    }
+
+    fn key(&mut self, vk_code: u32, value: i32) {
+        match vk_to_linux(vk_code) {
+            Some(code) => self.emit(&[RawEvent::key(code, value)]),
+            None => debug!("No Linux key code for vk {:#X}", vk_code),
+        }
+    }
+}
+
+impl<W: Write> InputSink for UinputSink<W> {
+    fn key_down(&mut self, vk_code: u32) {
+        self.key(vk_code, 1);
+    }
+    fn key_up(&mut self, vk_code: u32) {
+        self.key(vk_code, 0);
+    }
+    fn move_mouse(&mut self, dx: i32, dy: i32) {
+        self.emit(&[
+            RawEvent::new(EV_REL, REL_X, dx),
+            RawEvent::new(EV_REL, REL_Y, dy),
+        ]);
+    }
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        let mut events = Vec::new();
+        if dy != 0 {
+            events.push(RawEvent::new(EV_REL, REL_WHEEL_HI_RES, dy));
+            self.wheel_remainder_y += dy;
+            let detents = self.wheel_remainder_y / WHEEL_DELTA;
+            if detents != 0 {
+                self.wheel_remainder_y -= detents * WHEEL_DELTA;
+                events.push(RawEvent::new(EV_REL, REL_WHEEL, detents));
+            }
+        }
+        if dx != 0 {
+            events.push(RawEvent::new(EV_REL, REL_HWHEEL_HI_RES, dx));
+            self.wheel_remainder_x += dx;
+            let detents = self.wheel_remainder_x / WHEEL_DELTA;
+            if detents != 0 {
+                self.wheel_remainder_x -= detents * WHEEL_DELTA;
+                events.push(RawEvent::new(EV_REL, REL_HWHEEL, detents));
+            }
+        }
+        if !events.is_empty() {
+            self.emit(&events);
+        }
+    }
+    fn mouse_button(&mut self, button: MouseButton, down: bool) {
+        let code = match button {
+            MouseButton::Left => BTN_LEFT,
+            MouseButton::Right => BTN_RIGHT,
+            MouseButton::Middle => BTN_MIDDLE,
+        };
+        self.emit(&[RawEvent::key(code, down as i32)]);
+    }
+}
+
+/// Read raw events until the input ends, running key events through the engine.
+/// Keys the engine lets through, and keys it does not know, are re-emitted unchanged.
+pub fn run_loop<R: Read, W: Write>(
+    mut reader: R,
+    engine: &Mutex<Engine>,
+    sink: &Mutex<UinputSink<W>>,
+) -> io::Result<()> {
+    while let Some(event) = RawEvent::read_from(&mut reader)? {
+        if event.kind != EV_KEY {
+            // Our own writes carry their SYN_REPORTs; other event types are dropped.
+            continue;
+        }
+        let Some(vk_code) = linux_to_vk(event.code) else {
+            sink.lock().unwrap().emit(&[event]);
+            continue;
+        };
+        // value 0 is a release, 1 a press and 2 an autorepeat
+        let is_key_down = event.value != 0;
+        let decision = {
+            let mut engine = engine.lock().unwrap();
+            let now = engine.now();
+            engine.process(KeyEvent::new(vk_code, is_key_down, now))
+        };
+        if decision.propagate {
+            sink.lock().unwrap().emit(&[event]);
+        }
+        send_batch(sink, &decision.actions);
+    }
+    Ok(())
+}
+
+/// Send a batch, holding the sink only between its delays so the other threads'
+/// output isn't stuck behind them.
+fn send_batch<W: Write>(sink: &Mutex<UinputSink<W>>, actions: &[Action]) {
+    let mut start = 0;
+    for (index, action) in actions.iter().enumerate() {
+        if let Action::Delay(duration) = action {
+            if start < index {
+                sink.lock().unwrap().send_batch(&actions[start..index]);
+            }
+            thread::sleep(*duration);
+            start = index + 1;
+        }
+    }
+    if start < actions.len() {
+        sink.lock().unwrap().send_batch(&actions[start..]);
+    }
+}
+
+fn ioctl(file: &File, request: u64, arg: libc::c_ulong) -> io::Result<()> {
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Create the virtual keyboard and mouse everything is re-emitted through.
+fn create_uinput_device() -> io::Result<File> {
+    let file = OpenOptions::new().write(true).open("/dev/uinput")?;
+    ioctl(&file, UI_SET_EVBIT, EV_KEY as libc::c_ulong)?;
+    ioctl(&file, UI_SET_EVBIT, EV_REL as libc::c_ulong)?;
+    ioctl(&file, UI_SET_EVBIT, EV_SYN as libc::c_ulong)?;
+    // Every key and button, the mouse buttons the mouse mode clicks included.
+    for code in 1..=KEY_MAX {
+        ioctl(&file, UI_SET_KEYBIT, code as libc::c_ulong)?;
+    }
+    for code in [
+        REL_X,
+        REL_Y,
+        REL_WHEEL,
+        REL_HWHEEL,
+        REL_WHEEL_HI_RES,
+        REL_HWHEEL_HI_RES,
+    ] {
+        ioctl(&file, UI_SET_RELBIT, code as libc::c_ulong)?;
+    }
+    let mut setup: libc::uinput_setup = unsafe { mem::zeroed() };
+    setup.id.bustype = BUS_USB;
+    setup.id.vendor = 0x1209;
+    setup.id.product = 0xB051;
+    for (dst, src) in setup.name.iter_mut().zip(b"bushido keys".iter()) {
+        *dst = *src as libc::c_char;
+    }
+    ioctl(&file, UI_DEV_SETUP, &setup as *const _ as libc::c_ulong)?;
+    ioctl(&file, UI_DEV_CREATE, 0)?;
+    Ok(file)
+}
+
+/// The first keyboard listed under /dev/input/by-id.
+fn find_keyboard() -> Option<PathBuf> {
+    let mut keyboards: Vec<PathBuf> = fs::read_dir("/dev/input/by-id")
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.to_string_lossy().ends_with("-event-kbd"))
+        .collect();
+    keyboards.sort();
+    keyboards.into_iter().next()
+}
+
+/// Grab the keyboard and run until it goes away.
+/// `args` may hold `--device /dev/input/eventN`; otherwise the first keyboard is used.
+/// `config_dir` is where the control channel finds the config files.
+pub fn run(args: &[String], config_dir: &Path) -> io::Result<()> {
+    let device_path = match args.iter().position(|arg| arg == "--device") {
+        Some(index) => args.get(index + 1).map(PathBuf::from),
+        None => find_keyboard(),
+    }
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no keyboard device found"))?;
+    let uinput = create_uinput_device()?;
+    let sink = Arc::new(Mutex::new(UinputSink::new(uinput.try_clone()?)));
+
+    // Let the keys that launched us come up before the device is grabbed.
+    thread::sleep(Duration::from_millis(300));
+    let device = File::open(Path::new(&device_path))?;
+    ioctl(&device, EVIOCGRAB, 1)?;
+    info!("Grabbed {:?}", device_path);
+
+    // Update active mode thread
+    let update_sink = Arc::clone(&sink);
+    thread::spawn(move || loop {
+        let actions = ENGINE.lock().unwrap().update();
+        send_batch(&update_sink, &actions);
+        thread::sleep(Duration::from_millis(1));
+    });
+
+    // Answer `bushido_keys_rs ctl` and other local clients.
+    let control_sink = Arc::clone(&sink);
+    control::spawn(config_dir, &ENGINE, move |actions| {
+        send_batch(&control_sink, actions)
+    });
+
+    let result = run_loop(&device, &ENGINE, &sink);
+    let _ = ioctl(&uinput, UI_DEV_DESTROY, 0);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::modes_with;
+
+    const KEY_A: u16 = 30;
+    const KEY_D: u16 = 32;
+    const KEY_K: u16 = 37;
+    const KEY_COMMA: u16 = 51;
+    const KEY_LEFT: u16 = 105;
+
+    fn default_engine() -> Mutex<Engine> {
+        Mutex::new(Engine::new(modes_with(|_| {})))
+    }
+
+    /// Push raw events through a pipe and collect what comes out of the virtual device.
+    fn run_through_pipe(events: &[RawEvent]) -> Vec<RawEvent> {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        for event in events {
+            event.write_to(&mut writer).unwrap();
+        }
+        drop(writer);
+        let sink = Mutex::new(UinputSink::new(Vec::new()));
+        run_loop(reader, &default_engine(), &sink).unwrap();
+        let output = sink.into_inner().unwrap().into_inner();
+        let mut output = output.as_slice();
+        let mut events = Vec::new();
+        while let Some(event) = RawEvent::read_from(&mut output).unwrap() {
+            if event.kind != EV_SYN {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn key_codes_translate_both_ways() {
+        assert_eq!(linux_to_vk(KEY_A), Some('A' as u32));
+        assert_eq!(linux_to_vk(57), Some(0x20));
+        assert_eq!(vk_to_linux(0xBC), Some(KEY_COMMA));
+        assert_eq!(vk_to_linux(0x10), Some(KEY_LEFTSHIFT));
+        assert_eq!(linux_to_vk(0x2FF), None);
+    }
+
+    #[test]
+    fn keys_without_a_virtual_key_code_pass_through() {
+        // KEY_MACRO1, found on gaming keyboards
+        const KEY_MACRO1: u16 = 0x290;
+        assert_eq!(linux_to_vk(KEY_MACRO1), None);
+        let events = [RawEvent::key(KEY_MACRO1, 1), RawEvent::key(KEY_MACRO1, 0)];
+        assert_eq!(run_through_pipe(&events), events.to_vec());
+    }
+
+    #[test]
+    fn raw_events_round_trip() {
+        let mut buffer = Vec::new();
+        RawEvent::key(KEY_A, 1).write_to(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), mem::size_of::<libc::input_event>());
+        let mut bytes = buffer.as_slice();
+        assert_eq!(
+            RawEvent::read_from(&mut bytes).unwrap(),
+            Some(RawEvent::key(KEY_A, 1))
+        );
+        assert_eq!(RawEvent::read_from(&mut bytes).unwrap(), None);
+    }
+
+    #[test]
+    fn mapped_key_is_emitted_through_uinput() {
+        // K activates delimeter mode, D there is Shift+,
+        let output = run_through_pipe(&[
+            RawEvent::key(KEY_K, 1),
+            RawEvent::key(KEY_D, 1),
+            RawEvent::key(KEY_D, 0),
+        ]);
+        assert_eq!(
+            output,
+            vec![
+                RawEvent::key(KEY_LEFTSHIFT, 1),
+                RawEvent::key(KEY_COMMA, 1),
+                RawEvent::key(KEY_COMMA, 0),
+                RawEvent::key(KEY_LEFTSHIFT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn scroll_reports_whole_detents_once_accumulated() {
+        let mut sink = UinputSink::new(Vec::new());
+        sink.scroll(0, 80);
+        sink.scroll(0, 80);
+        let output = sink.into_inner();
+        let mut output = output.as_slice();
+        let mut events = Vec::new();
+        while let Some(event) = RawEvent::read_from(&mut output).unwrap() {
+            if event.kind == EV_REL {
+                events.push(event);
+            }
+        }
+        assert_eq!(
+            events,
+            vec![
+                RawEvent::new(EV_REL, REL_WHEEL_HI_RES, 80),
+                RawEvent::new(EV_REL, REL_WHEEL_HI_RES, 80),
+                RawEvent::new(EV_REL, REL_WHEEL, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn forwarded_and_unknown_keys_pass_through() {
+        let unknown = RawEvent::key(0x2FF, 1);
+        let output = run_through_pipe(&[
+            RawEvent::key(KEY_LEFT, 1),
+            RawEvent::new(EV_SYN, SYN_REPORT, 0),
+            unknown,
+        ]);
+        assert_eq!(output, vec![RawEvent::key(KEY_LEFT, 1), unknown]);
+    }
+}
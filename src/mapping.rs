@@ -0,0 +1,430 @@
+// mapping.rs
+//! The output of a key mapping, resolved from `KeyMappingEntry` to virtual key codes.
+use crate::conversion::{
+    char_to_key_tap, key_tap_to_char, string_to_modifier, string_to_vk, try_string_to_modifier,
+};
+use crate::input_sink::{key_tap_with_auto_modifiers, InputSink};
+use crate::key_and_modifiers::KeyAndModifiers;
+use crate::mode_config::{KeyMappingEntry, RepeatConfig, StepConfig};
+use std::time::Duration;
+
+/// Opening delimiters and the partner auto-pairing types after them.
+const PAIRS: &[(char, char)] = &[
+    ('(', ')'),
+    ('[', ']'),
+    ('{', '}'),
+    ('<', '>'),
+    ('"', '"'),
+    ('\'', '\''),
+];
+const SHIFT_KEYS: &[u32] = &[0x10, 0xA0, 0xA1];
+const LEFT_ARROW: u32 = 0x25;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputStep {
+    Tap(KeyAndModifiers),
+    Down(u32),
+    Up(u32),
+    Text(String),
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    /// A plain `{key, modifiers}` entry is a single tap.
+    pub steps: Vec<OutputStep>,
+    /// Whether the entry spelled out steps or text. Those are emitted exactly as written;
+    /// only a plain key picks up the held shift and the mode's auto modifiers.
+    pub is_exact: bool,
+    /// Auto-repeat while the source key is held, if enabled.
+    pub repeat: Option<RepeatConfig>,
+    /// Close an opening delimiter right away and step back between the pair.
+    pub auto_pair: bool,
+    /// Outputs chosen by tap count instead of `steps`; empty for ordinary mappings.
+    pub tap_dance: Vec<DanceStep>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DanceStep {
+    pub taps: u32,
+    pub hold: bool,
+    pub mapping: Mapping,
+}
+
+/// Down and up steps take key names as well as modifier names such as LCTRL.
+fn step_key_to_vk(name: &str) -> u32 {
+    try_string_to_modifier(name).unwrap_or_else(|| string_to_vk(name))
+}
+
+impl Mapping {
+    /// Resolve a config entry; `mode_repeat` applies unless the entry sets its own.
+    pub fn new(entry: &KeyMappingEntry, mode_repeat: Option<RepeatConfig>) -> Self {
+        if !entry.tap_dance.is_empty() {
+            // The key's release is part of the dance, so it never auto-repeats.
+            return Self {
+                steps: Vec::new(),
+                is_exact: true,
+                repeat: None,
+                auto_pair: false,
+                tap_dance: entry
+                    .tap_dance
+                    .iter()
+                    .map(|step| DanceStep {
+                        taps: step.taps,
+                        hold: step.hold,
+                        mapping: Mapping::new(&step.output, None),
+                    })
+                    .collect(),
+            };
+        }
+        let repeat = match &entry.repeat {
+            Some(repeat) => repeat.resolve(mode_repeat),
+            None => mode_repeat,
+        };
+        if let Some(key) = &entry.key {
+            return Self {
+                steps: vec![OutputStep::Tap(KeyAndModifiers {
+                    key: string_to_vk(key),
+                    modifiers: entry
+                        .modifiers
+                        .iter()
+                        .map(|m| string_to_modifier(m))
+                        .collect(),
+                })],
+                is_exact: false,
+                repeat,
+                auto_pair: false,
+                tap_dance: Vec::new(),
+            };
+        }
+        if let Some(text) = &entry.text {
+            return Self {
+                steps: vec![OutputStep::Text(text.clone())],
+                is_exact: true,
+                repeat,
+                auto_pair: false,
+                tap_dance: Vec::new(),
+            };
+        }
+        let steps = entry
+            .steps
+            .iter()
+            .map(|step| match step {
+                StepConfig::Tap { tap, modifiers } => OutputStep::Tap(KeyAndModifiers {
+                    key: string_to_vk(tap),
+                    modifiers: modifiers.iter().map(|m| string_to_modifier(m)).collect(),
+                }),
+                StepConfig::Down { down } => OutputStep::Down(step_key_to_vk(down)),
+                StepConfig::Up { up } => OutputStep::Up(step_key_to_vk(up)),
+                StepConfig::Text { text } => OutputStep::Text(text.clone()),
+                StepConfig::Delay { delay_ms } => {
+                    OutputStep::Delay(Duration::from_millis(*delay_ms))
+                }
+            })
+            .collect();
+        Self {
+            steps,
+            is_exact: true,
+            repeat,
+            auto_pair: false,
+            tap_dance: Vec::new(),
+        }
+    }
+
+    pub fn with_auto_pair(mut self, auto_pair: bool) -> Self {
+        self.auto_pair = auto_pair;
+        for step in self.tap_dance.iter_mut() {
+            step.mapping.auto_pair = auto_pair;
+        }
+        self
+    }
+
+    pub fn is_tap_dance(&self) -> bool {
+        !self.tap_dance.is_empty()
+    }
+
+    /// The most taps any output of the dance waits for.
+    pub fn max_taps(&self) -> u32 {
+        self.tap_dance
+            .iter()
+            .map(|step| step.taps)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The output for `taps` taps, the last one held if `hold` is set. A tap count
+    /// without its own output uses the closest one below it; holding only has an
+    /// output where one is configured.
+    pub fn dance_step(&self, taps: u32, hold: bool) -> Option<&Mapping> {
+        let steps = self.tap_dance.iter().filter(|step| step.hold == hold);
+        if hold {
+            return steps
+                .into_iter()
+                .find(|step| step.taps == taps)
+                .map(|step| &step.mapping);
+        }
+        steps
+            .filter(|step| step.taps <= taps)
+            .max_by_key(|step| step.taps)
+            .map(|step| &step.mapping)
+    }
+
+    /// The closing partner to type after this mapping, if auto-pairing applies.
+    /// Only a single key tap with no modifiers but shift, or a single text step,
+    /// can type an opening delimiter.
+    fn closing_partner(&self, shift: bool, auto_modifiers: &[u32]) -> Option<char> {
+        if !self.auto_pair {
+            return None;
+        }
+        let opening = match self.steps.as_slice() {
+            [OutputStep::Tap(tap)] if !self.is_exact && auto_modifiers.is_empty() => {
+                if !tap.modifiers.iter().all(|m| SHIFT_KEYS.contains(m)) {
+                    return None;
+                }
+                key_tap_to_char(tap.key, shift || !tap.modifiers.is_empty())?
+            }
+            [OutputStep::Text(text)] => {
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        PAIRS
+            .iter()
+            .find(|(open, _)| *open == opening)
+            .map(|(_, close)| *close)
+    }
+
+    /// Send every step to the sink, in order.
+    pub fn emit(&self, shift: bool, auto_modifiers: &[u32], sink: &mut dyn InputSink) {
+        let shift_modifier: &[u32] = if shift && !self.is_exact {
+            &[0x10]
+        } else {
+            &[]
+        };
+        let auto_modifiers = if self.is_exact { &[] } else { auto_modifiers };
+        let closing_partner = self.closing_partner(shift, auto_modifiers);
+        for step in self.steps.iter() {
+            match step {
+                OutputStep::Tap(tap) => {
+                    // combine the modifiers with the auto modifiers
+                    let modifiers = [&tap.modifiers[..], shift_modifier].concat();
+                    key_tap_with_auto_modifiers(sink, tap.key, &modifiers, auto_modifiers);
+                }
+                OutputStep::Down(vk_code) => sink.key_down(*vk_code),
+                OutputStep::Up(vk_code) => sink.key_up(*vk_code),
+                OutputStep::Text(text) => sink.text(text),
+                OutputStep::Delay(duration) => sink.delay(*duration),
+            }
+        }
+        if let Some(close) = closing_partner {
+            if self.is_exact {
+                sink.text(&close.to_string());
+            } else if let Some((vk_code, is_shifted)) = char_to_key_tap(close) {
+                let modifiers: &[u32] = if is_shifted { &[0x10] } else { &[] };
+                sink.key_tap(vk_code, modifiers);
+            }
+            sink.key_tap(LEFT_ARROW, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_sink::{Action, RecordingSink};
+
+    fn entry(json: &str) -> KeyMappingEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn plain_entry_is_one_tap_with_shift_and_auto_modifiers() {
+        let mapping = Mapping::new(&entry(r#"{"key": "9", "modifiers": ["SHIFT"]}"#), None);
+        let mut sink = RecordingSink::new();
+        mapping.emit(true, &[0x11], &mut sink);
+        assert_eq!(
+            sink.actions,
+            vec![Action::KeyTap {
+                vk_code: '9' as u32,
+                modifiers: vec![0x10, 0x10, 0x11]
+            }]
+        );
+    }
+
+    #[test]
+    fn steps_are_emitted_as_written() {
+        let mapping = Mapping::new(
+            &entry(
+                r#"{"steps": [
+                    {"tap": "9", "modifiers": ["SHIFT"]},
+                    {"tap": "0", "modifiers": ["SHIFT"]},
+                    {"delay_ms": 10},
+                    {"down": "LCTRL"},
+                    {"tap": "K"},
+                    {"up": "LCTRL"},
+                    {"tap": "LEFT"}
+                ]}"#,
+            ),
+            None,
+        );
+        let mut sink = RecordingSink::new();
+        mapping.emit(true, &[0x11], &mut sink);
+        assert_eq!(
+            sink.actions,
+            vec![
+                Action::KeyTap {
+                    vk_code: '9' as u32,
+                    modifiers: vec![0x10]
+                },
+                Action::KeyTap {
+                    vk_code: '0' as u32,
+                    modifiers: vec![0x10]
+                },
+                Action::Delay(Duration::from_millis(10)),
+                Action::KeyDown(0xA2),
+                Action::KeyTap {
+                    vk_code: 'K' as u32,
+                    modifiers: vec![]
+                },
+                Action::KeyUp(0xA2),
+                Action::KeyTap {
+                    vk_code: 0x25,
+                    modifiers: vec![]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mappings_can_leave_the_mode_repeat() {
+        let mode_repeat = Some(RepeatConfig {
+            delay_ms: 200,
+            interval_ms: 30,
+        });
+        let repeat_of = |json| Mapping::new(&entry(json), mode_repeat).repeat;
+        assert_eq!(repeat_of(r#"{"key": "LEFT"}"#), mode_repeat);
+        assert_eq!(repeat_of(r#"{"key": "P", "repeat": false}"#), None);
+        assert_eq!(repeat_of(r#"{"key": "LEFT", "repeat": true}"#), mode_repeat);
+        assert_eq!(
+            repeat_of(r#"{"key": "LEFT", "repeat": {"interval_ms": 100}}"#),
+            Some(RepeatConfig {
+                delay_ms: 300,
+                interval_ms: 100
+            })
+        );
+        let plain = entry(r#"{"key": "LEFT", "repeat": true}"#);
+        assert_eq!(
+            Mapping::new(&plain, None).repeat,
+            Some(RepeatConfig::default())
+        );
+    }
+
+    #[test]
+    fn text_entry_types_its_text() {
+        let mapping = Mapping::new(&entry(r#"{"text": "TODO(team): "}"#), None);
+        let mut sink = RecordingSink::new();
+        mapping.emit(true, &[0x11], &mut sink);
+        assert_eq!(sink.actions, vec![Action::Text("TODO(team): ".to_string())]);
+    }
+
+    #[test]
+    fn auto_pair_closes_opening_delimiters() {
+        let paren = Mapping::new(&entry(r#"{"key": "9", "modifiers": ["SHIFT"]}"#), None)
+            .with_auto_pair(true);
+        let mut sink = RecordingSink::new();
+        paren.emit(false, &[], &mut sink);
+        assert_eq!(
+            sink.actions,
+            vec![
+                Action::KeyTap {
+                    vk_code: 0x39,
+                    modifiers: vec![0x10]
+                },
+                Action::KeyTap {
+                    vk_code: 0x30,
+                    modifiers: vec![0x10]
+                },
+                Action::KeyTap {
+                    vk_code: LEFT_ARROW,
+                    modifiers: vec![]
+                },
+            ]
+        );
+
+        // Holding shift turns "[" into "{", which closes with "}".
+        let bracket = Mapping::new(&entry(r#"{"key": "["}"#), None).with_auto_pair(true);
+        let mut sink = RecordingSink::new();
+        bracket.emit(true, &[], &mut sink);
+        assert_eq!(
+            sink.actions[1],
+            Action::KeyTap {
+                vk_code: 0xDD,
+                modifiers: vec![0x10]
+            }
+        );
+
+        let quote = Mapping::new(&entry(r#"{"text": "\""}"#), None).with_auto_pair(true);
+        let mut sink = RecordingSink::new();
+        quote.emit(false, &[], &mut sink);
+        assert_eq!(
+            sink.actions,
+            vec![
+                Action::Text("\"".to_string()),
+                Action::Text("\"".to_string()),
+                Action::KeyTap {
+                    vk_code: LEFT_ARROW,
+                    modifiers: vec![]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn auto_pair_leaves_other_output_alone() {
+        for json in [
+            r#"{"key": "0", "modifiers": ["SHIFT"]}"#,
+            r#"{"key": "9", "modifiers": ["CTRL"]}"#,
+            r#"{"text": "(x"}"#,
+            r#"{"steps": [{"tap": "9", "modifiers": ["SHIFT"]}]}"#,
+        ] {
+            let mapping = Mapping::new(&entry(json), None).with_auto_pair(true);
+            let mut sink = RecordingSink::new();
+            mapping.emit(false, &[], &mut sink);
+            assert_eq!(sink.actions.len(), 1, "{}", json);
+        }
+        let mapping = Mapping::new(&entry(r#"{"key": "["}"#), None).with_auto_pair(true);
+        let mut sink = RecordingSink::new();
+        mapping.emit(false, &[0x11], &mut sink);
+        assert_eq!(sink.actions.len(), 1);
+    }
+
+    #[test]
+    fn tap_dance_falls_back_to_fewer_taps() {
+        let mapping = Mapping::new(
+            &entry(
+                r#"{"tap_dance": [
+                    {"taps": 1, "key": "="},
+                    {"taps": 2, "text": "=="},
+                    {"taps": 1, "hold": true, "text": "!="}
+                ]}"#,
+            ),
+            None,
+        );
+        assert!(mapping.is_tap_dance());
+        assert_eq!(mapping.max_taps(), 2);
+        let steps = |taps, hold| mapping.dance_step(taps, hold).map(|m| m.steps.clone());
+        assert_eq!(
+            steps(3, false),
+            Some(vec![OutputStep::Text("==".to_string())])
+        );
+        assert_eq!(
+            steps(1, true),
+            Some(vec![OutputStep::Text("!=".to_string())])
+        );
+        assert_eq!(steps(2, true), None);
+    }
+}
@@ -1,16 +1,54 @@
 // mode.rs
-use crate::key_and_modifiers::KeyAndModifiers;
+use crate::app_context::AppFilter;
+use crate::input_sink::InputSink;
 use crate::key_state::KeyState;
-use std::collections::HashMap;
+use crate::mode_config::{ActivationStyle, TapHoldStrategy};
+use std::time::{Duration, Instant};
+
+/// How a mode is entered and left, resolved from its config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Activation {
+    pub style: ActivationStyle,
+    /// Leaves the mode while it is locked on.
+    pub exit_key: Option<u32>,
+    /// Longest gap between the two taps that lock a `DoubleTapLock` mode.
+    pub double_tap: Duration,
+    /// How long a `OneShot` mode waits for its key before turning off.
+    pub one_shot_timeout: Duration,
+    pub tap_hold: TapHoldStrategy,
+    /// How long a `Balanced` key must be held before another key's tap makes it a hold.
+    pub balanced_min: Duration,
+    /// How long after the last key typed outside any mode the mode may be entered;
+    /// sooner, its activation key types itself.
+    pub require_idle: Option<Duration>,
+}
 
 pub trait Mode: Send {
-    fn handle_key_down_event(&mut self, key_state: &mut KeyState) -> bool;
-    fn handle_key_up_event(&mut self, key_state: &mut KeyState) -> bool;
-    fn update(&mut self);
+    fn handle_key_down_event(&mut self, key_state: &mut KeyState, sink: &mut dyn InputSink)
+        -> bool;
+    fn handle_key_up_event(&mut self, key_state: &mut KeyState, sink: &mut dyn InputSink) -> bool;
+    /// Periodic work such as mouse movement; `now` comes from the engine's clock.
+    fn update(&mut self, now: Instant, sink: &mut dyn InputSink);
     fn get_name(&self) -> &str;
     fn get_activation_keys(&self) -> &Vec<u32>;
+    /// Keys that activate the mode when they all go down within `get_chord_window`.
+    /// Empty for modes activated by a single key.
+    fn get_activation_chord(&self) -> &[u32];
+    fn get_chord_window(&self) -> Duration;
+    fn get_activation(&self) -> &Activation;
+    /// When a tap locked the mode on, keeping it active without its activation key held.
+    fn get_locked_at(&self) -> Option<Instant>;
+    fn set_locked_at(&mut self, locked_at: Option<Instant>);
+    fn is_locked(&self) -> bool {
+        self.get_locked_at().is_some()
+    }
+    /// Whether this mode has its own output for `vk_code`. Keys it doesn't map
+    /// fall through to the mode below it on the stack.
+    fn maps_key(&self, vk_code: u32) -> bool;
+    /// Releasing the activation key sooner than this types the key instead.
+    fn get_tap_timeout(&self) -> Duration;
     /// Check if a key-up event should deactivate this mode.
-    fn check_if_deactivates<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool;
+    fn check_if_deactivates(&mut self, key_state: &mut KeyState) -> bool;
 
     /// Clone this mode as a boxed trait object.
     fn clone_box(&self) -> Box<dyn Mode + Send>;
@@ -19,8 +57,25 @@ pub trait Mode: Send {
     fn get_auto_modifiers(&self) -> &Vec<u32>;
     fn was_mode_used(&self) -> bool;
     fn set_was_mode_used(&mut self, was_mode_used: bool);
-    fn was_repeat(&self) -> bool;
-    fn set_was_repeat(&mut self, was_repeat: bool);
+    /// Stop auto-repeat and return the held keys that already repeated,
+    /// so their release isn't typed again once the mode is gone.
+    fn take_repeated_keys(&mut self) -> Vec<u32>;
+    /// Type output still waiting on more input, such as an unfinished tap dance,
+    /// before the mode is taken out of the stack.
+    fn finish_pending(&mut self, _sink: &mut dyn InputSink) {}
+    /// Whether the mode is done and leaves the stack on its own, like a leader
+    /// mode whose sequence completed.
+    fn is_finished(&self) -> bool {
+        false
+    }
+    /// The applications the mode is limited to, if any.
+    fn get_app_filter(&self) -> Option<&AppFilter> {
+        None
+    }
+    /// The mappings that produced output since the last call, by their name in the config.
+    fn take_used_mappings(&mut self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 impl Clone for Box<dyn Mode + Send> {
@@ -28,12 +83,3 @@ impl Clone for Box<dyn Mode + Send> {
         self.clone_box()
     }
 }
-
-#[derive(Clone)]
-pub struct BasicMode {
-    pub name: String,
-    pub key_mapping: HashMap<u32, KeyAndModifiers>,
-    pub activation_keys: Vec<u32>,
-    pub key_code_activated_by: Option<u32>,
-    pub auto_modifiers: Vec<u32>,
-}
@@ -1,39 +1,308 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
+/// How long an activation key may be held and still type itself when released.
+pub const DEFAULT_TAP_TIMEOUT_MS: u64 = 200;
+/// Mapped keys held longer than this produce no output.
+pub const DEFAULT_HOLD_CUTOFF_MS: u64 = 500;
+/// How close together the keys of an activation chord must go down.
+pub const DEFAULT_CHORD_WINDOW_MS: u64 = 50;
+/// Longest gap between the two taps that lock a `double_tap_lock` mode.
+pub const DEFAULT_DOUBLE_TAP_MS: u64 = 300;
+/// How long a tapped `one_shot` mode waits for the next key.
+pub const DEFAULT_ONE_SHOT_TIMEOUT_MS: u64 = 1000;
+/// How long a `balanced` activation key must be held before another key's tap makes it a hold.
+pub const DEFAULT_BALANCED_MIN_MS: u64 = 100;
+/// How long a tap-dance key waits for its next tap before typing.
+pub const DEFAULT_TAPPING_TERM_MS: u64 = 200;
+/// How long a leader mode waits for the next key of a sequence.
+pub const DEFAULT_SEQUENCE_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_REPEAT_DELAY_MS: u64 = 300;
+pub const DEFAULT_REPEAT_INTERVAL_MS: u64 = 50;
+
+/// Auto-repeat of a mapped key held inside a mode.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RepeatConfig {
+    /// How long the key is held before the first repeat.
+    #[serde(default = "default_repeat_delay_ms")]
+    pub delay_ms: u64,
+    /// Time between repeats after that.
+    #[serde(default = "default_repeat_interval_ms")]
+    pub interval_ms: u64,
+}
+
+impl RepeatConfig {
+    pub fn get_delay(&self) -> Duration {
+        Duration::from_millis(self.delay_ms)
+    }
+    pub fn get_interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl Default for RepeatConfig {
+    fn default() -> Self {
+        Self {
+            delay_ms: DEFAULT_REPEAT_DELAY_MS,
+            interval_ms: DEFAULT_REPEAT_INTERVAL_MS,
+        }
+    }
+}
+
+/// A mapping's own auto-repeat: `false` leaves it out of its mode's repeat, `true`
+/// repeats it with the mode's timing (or the defaults), an object sets its own timing.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MappingRepeat {
+    Switch(bool),
+    Timing(RepeatConfig),
+}
+
+impl MappingRepeat {
+    pub fn resolve(&self, mode_repeat: Option<RepeatConfig>) -> Option<RepeatConfig> {
+        match self {
+            MappingRepeat::Switch(false) => None,
+            MappingRepeat::Switch(true) => Some(mode_repeat.unwrap_or_default()),
+            MappingRepeat::Timing(repeat) => Some(*repeat),
+        }
+    }
+}
+
+fn default_repeat_delay_ms() -> u64 {
+    DEFAULT_REPEAT_DELAY_MS
+}
+
+fn default_repeat_interval_ms() -> u64 {
+    DEFAULT_REPEAT_INTERVAL_MS
+}
+
+/// How a mode stays active after its activation key is pressed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivationStyle {
+    /// Active while the activation key is held.
+    #[default]
+    Hold,
+    /// A quick tap locks the mode on; tapping the key again leaves it.
+    /// Holding the key still works like `Hold`.
+    Toggle,
+    /// Like `Hold`, but tapping the key twice in quick succession locks the mode on.
+    DoubleTapLock,
+    /// A quick tap turns the mode on for the next key only.
+    /// Holding the key still works like `Hold`.
+    OneShot,
+}
+
+/// How a held activation key is told apart from a tap while other keys are typed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TapHoldStrategy {
+    /// The mode is active right away; releasing the key within tap_timeout_ms,
+    /// before any key was released inside the mode, types the key instead.
+    #[default]
+    Timeout,
+    /// Pressing any other key makes it a hold.
+    HoldOnOtherKeyPress,
+    /// Another key pressed and released while the key is held makes it a hold.
+    PermissiveHold,
+    /// Like `PermissiveHold`, but only once the key has been held for balanced_min_ms.
+    Balanced,
+}
+
+/// One step of a multi-step mapping.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StepConfig {
+    /// `{"tap": "K", "modifiers": ["CTRL"]}`
+    Tap {
+        tap: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        modifiers: Vec<String>,
+    },
+    /// `{"down": "CTRL"}`
+    Down { down: String },
+    /// `{"up": "CTRL"}`
+    Up { up: String },
+    /// `{"text": "λ"}`
+    Text { text: String },
+    /// `{"delay_ms": 20}`
+    Delay { delay_ms: u64 },
+}
+
+/// Limits a mode to the applications it matches, or with `exclude` to all others.
+/// Every field that is set has to match.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AppMatch {
+    /// The executable's file name, e.g. `Code.exe`, compared ignoring case.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_class: Option<String>,
+    /// A regular expression searched for in the window title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub exclude: bool,
+}
+
+/// One output of a tap-dance key: typed after `taps` quick taps, or once the last
+/// of them is held if `hold` is set.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TapDanceStep {
+    pub taps: u32,
+    #[serde(default)]
+    pub hold: bool,
+    #[serde(flatten)]
+    pub output: KeyMappingEntry,
+}
+
+/// One of: a single `key` with `modifiers`, `text` to type, an ordered list of `steps`,
+/// or a `tap_dance` choosing between outputs by how often the key is tapped.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KeyMappingEntry {
-    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
     #[serde(default)]
     pub modifiers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tap_dance: Vec<TapDanceStep>,
+    /// Overrides the mode's repeat for this mapping.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<MappingRepeat>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ModeConfig {
     pub name: String,
+    #[serde(default)]
     pub activation_keys: Vec<String>,
+    /// Only activate the mode in the applications this matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_match: Option<AppMatch>,
+    /// Keys that activate the mode only when all of them go down together.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub activation_chord: Vec<String>,
+    /// Overrides the global chord_window_ms for this mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chord_window_ms: Option<u64>,
+    #[serde(default)]
+    pub activation_style: ActivationStyle,
+    /// Leaves the mode while it is locked on, in addition to its activation key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub double_tap_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_shot_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub tap_hold: TapHoldStrategy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balanced_min_ms: Option<u64>,
+    /// Only enter the mode once this long has passed since the last key typed outside
+    /// any mode; sooner, the activation key is typed as a plain letter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_idle_ms: Option<u64>,
+    #[serde(default)]
     pub key_mapping: HashMap<String, KeyMappingEntry>,
+    #[serde(default)]
     pub auto_modifiers: Vec<String>,
+    /// Makes this a leader mode: key sequences such as `"G S"`, typed after tapping
+    /// the activation key, mapped to their output.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sequences: HashMap<String, KeyMappingEntry>,
+    /// How long a leader mode waits for the next key of a sequence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_timeout_ms: Option<u64>,
+    /// Type the keys of a sequence that matches nothing instead of dropping them.
+    #[serde(default)]
+    pub replay_unmatched: bool,
+    /// Overrides the global tap_timeout_ms for this mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tap_timeout_ms: Option<u64>,
+    /// Overrides the global hold_cutoff_ms for this mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_cutoff_ms: Option<u64>,
+    /// Auto-repeat for every mapping in this mode; mappings can set their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<RepeatConfig>,
+    /// Overrides the global tapping_term_ms for this mode's tap-dance keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tapping_term_ms: Option<u64>,
+    /// Follow every opening delimiter this mode types with its closing partner,
+    /// leaving the cursor between the two.
+    #[serde(default)]
+    pub auto_pair: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ModesConfig {
     pub modes: Vec<ModeConfig>, 
+    #[serde(default = "default_tap_timeout_ms")]
+    pub tap_timeout_ms: u64,
+    #[serde(default = "default_hold_cutoff_ms")]
+    pub hold_cutoff_ms: u64,
+    #[serde(default = "default_chord_window_ms")]
+    pub chord_window_ms: u64,
+    #[serde(default = "default_tapping_term_ms")]
+    pub tapping_term_ms: u64,
+}
+
+fn default_tap_timeout_ms() -> u64 {
+    DEFAULT_TAP_TIMEOUT_MS
+}
+
+fn default_hold_cutoff_ms() -> u64 {
+    DEFAULT_HOLD_CUTOFF_MS
+}
+
+fn default_chord_window_ms() -> u64 {
+    DEFAULT_CHORD_WINDOW_MS
+}
+
+fn default_tapping_term_ms() -> u64 {
+    DEFAULT_TAPPING_TERM_MS
 }
 
 impl ModeConfig {
     pub fn get_name(&self) -> &str {
         &self.name
     }
-
-    pub fn get_activation_keys(&self) -> &Vec<String> {
-        &self.activation_keys
+    pub fn get_tap_timeout(&self) -> Duration {
+        Duration::from_millis(self.tap_timeout_ms.unwrap_or(DEFAULT_TAP_TIMEOUT_MS))
     }
-
-    pub fn get_key_mapping(&self) -> &std::collections::HashMap<String, KeyMappingEntry> {
-        &self.key_mapping
+    pub fn get_hold_cutoff(&self) -> Duration {
+        Duration::from_millis(self.hold_cutoff_ms.unwrap_or(DEFAULT_HOLD_CUTOFF_MS))
+    }
+    pub fn get_chord_window(&self) -> Duration {
+        Duration::from_millis(self.chord_window_ms.unwrap_or(DEFAULT_CHORD_WINDOW_MS))
+    }
+    pub fn get_double_tap(&self) -> Duration {
+        Duration::from_millis(self.double_tap_ms.unwrap_or(DEFAULT_DOUBLE_TAP_MS))
+    }
+    pub fn get_tapping_term(&self) -> Duration {
+        Duration::from_millis(self.tapping_term_ms.unwrap_or(DEFAULT_TAPPING_TERM_MS))
+    }
+    pub fn get_sequence_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.sequence_timeout_ms
+                .unwrap_or(DEFAULT_SEQUENCE_TIMEOUT_MS),
+        )
+    }
+    pub fn get_balanced_min(&self) -> Duration {
+        Duration::from_millis(self.balanced_min_ms.unwrap_or(DEFAULT_BALANCED_MIN_MS))
+    }
+    pub fn get_require_idle(&self) -> Option<Duration> {
+        self.require_idle_ms.map(Duration::from_millis)
     }
-    pub fn get_auto_modifiers(&self) -> &Vec<String> {
-        &self.auto_modifiers
+    pub fn get_one_shot_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.one_shot_timeout_ms
+                .unwrap_or(DEFAULT_ONE_SHOT_TIMEOUT_MS),
+        )
     }
 }
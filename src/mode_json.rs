@@ -2,6 +2,8 @@ use quote::quote;
 
 pub fn get_json_str() -> String {
     quote! {{
+      "tap_timeout_ms": 200,
+      "hold_cutoff_ms": 500,
       "modes": [
         {
           "name": "num_mode",
@@ -172,7 +174,12 @@ pub fn get_json_str() -> String {
             "K"
           ],
           "auto_modifiers": [],
+          "auto_pair": false,
           "key_mapping": {
+            "R": {
+              "key": "DELETE",
+              "modifiers": []
+            },
             "H": {
               "key": "|",
               "modifiers": [
@@ -238,24 +245,31 @@ pub fn get_json_str() -> String {
             "J"
           ],
           "auto_modifiers": [],
+          "repeat": {
+            "delay_ms": 300,
+            "interval_ms": 50
+          },
           "key_mapping": {
             "C": {
               "key": "C",
               "modifiers": [
                 "CTRL"
-              ]
+              ],
+              "repeat": false
             },
             "V": {
               "key": "V",
               "modifiers": [
                 "RWIN"
-              ]
+              ],
+              "repeat": false
             },
             "P": {
               "key": "P",
               "modifiers": [
                 "CTRL"
-              ]
+              ],
+              "repeat": false
             },
             "K": {
               "key": "LEFT",
@@ -283,11 +297,13 @@ pub fn get_json_str() -> String {
             },
             "CAPSLOCK": {
               "key": "HOME",
-              "modifiers": []
+              "modifiers": [],
+              "repeat": false
             },
             "G": {
               "key": "END",
-              "modifiers": []
+              "modifiers": [],
+              "repeat": false
             },
             "R": {
               "key": "PAGEUP",
@@ -315,11 +331,13 @@ pub fn get_json_str() -> String {
             },
             "'": {
               "key": "END",
-              "modifiers": []
+              "modifiers": [],
+              "repeat": false
             },
             "H": {
               "key": "HOME",
-              "modifiers": []
+              "modifiers": [],
+              "repeat": false
             }
           }
         },
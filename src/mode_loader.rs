@@ -0,0 +1,567 @@
+// mode_loader.rs
+use crate::app_context::AppFilter;
+use crate::basic_mode::BasicMode;
+use crate::conversion::{
+    suggest_names, try_string_to_modifier, try_string_to_vk, KEY_NAMES, MODIFIER_NAMES,
+};
+use crate::leader_mode::{sequence_keys, LeaderMode};
+use crate::mode::Mode;
+use crate::mode_config::{KeyMappingEntry, MappingRepeat, ModeConfig, ModesConfig, StepConfig};
+use crate::mouse_mode::{MouseConfig, MouseMode};
+use log::info;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// `~/.bushido_keys_config`, for the tools that run alongside the keyboard hook.
+pub fn config_dir() -> Result<PathBuf, String> {
+    let home_dir = env::var("USERPROFILE")
+        .or_else(|_| env::var("HOME"))
+        .map_err(|_| "no home directory to find .bushido_keys_config in".to_string())?;
+    Ok(Path::new(&home_dir).join(".bushido_keys_config"))
+}
+
+/// Read and parse a JSON config file; errors name the file.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Turn the parsed configuration into the list of modes the engine can activate.
+/// Fails if any mode, or the mouse config, uses a key or modifier name that isn't known.
+/// Modes without their own tap_timeout_ms, hold_cutoff_ms, chord_window_ms or tapping_term_ms
+/// get the global values.
+pub fn build_available_modes(
+    modes_config: ModesConfig,
+    mut mouse_mode: MouseMode,
+) -> Result<Vec<Box<dyn Mode + Send>>, String> {
+    let problems: Vec<String> = [
+        validate_modes_config(&modes_config),
+        validate_mouse_config(&mouse_mode.config),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect();
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+    let mut available_modes: Vec<Box<dyn Mode + Send>> = Vec::new();
+    for mut mode_cfg in modes_config.modes {
+        mode_cfg
+            .tap_timeout_ms
+            .get_or_insert(modes_config.tap_timeout_ms);
+        mode_cfg
+            .hold_cutoff_ms
+            .get_or_insert(modes_config.hold_cutoff_ms);
+        mode_cfg
+            .chord_window_ms
+            .get_or_insert(modes_config.chord_window_ms);
+        mode_cfg
+            .tapping_term_ms
+            .get_or_insert(modes_config.tapping_term_ms);
+        let mode_instance: Box<dyn Mode + Send> = if mode_cfg.sequences.is_empty() {
+            Box::new(BasicMode::new(mode_cfg))
+        } else {
+            Box::new(LeaderMode::new(mode_cfg))
+        };
+        info!("Loaded mode: {}", mode_instance.get_name());
+        info!("Activation keys: {:?}", mode_instance.get_activation_keys());
+        available_modes.push(mode_instance);
+    }
+    info!("Loaded modes config about to add mouse mode");
+    mouse_mode
+        .config
+        .tap_timeout_ms
+        .get_or_insert(modes_config.tap_timeout_ms);
+    available_modes.push(Box::new(mouse_mode));
+    info!("Added mouse mode");
+    Ok(available_modes)
+}
+
+/// Check every key and modifier name in the config, reporting all unknown names at once.
+pub fn validate_modes_config(modes_config: &ModesConfig) -> Result<(), String> {
+    let mut problems = Vec::new();
+    for mode in modes_config.modes.iter() {
+        check_mode(mode, &mut problems);
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+/// Check the key names in `mouse_config.json`.
+pub fn validate_mouse_config(mouse_config: &MouseConfig) -> Result<(), String> {
+    match &mouse_config.exit_key {
+        Some(exit_key) if try_string_to_vk(exit_key).is_none() => Err(format!(
+            "mouse_config.json, exit_key: {}",
+            unknown("key", exit_key, KEY_NAMES)
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_mode(mode: &ModeConfig, problems: &mut Vec<String>) {
+    let name = mode.get_name();
+    for key in mode.activation_keys.iter() {
+        if try_string_to_vk(key).is_none() {
+            problems.push(format!(
+                "mode \"{}\", activation_keys: {}",
+                name,
+                unknown("key", key, KEY_NAMES)
+            ));
+        }
+    }
+    for key in mode.activation_chord.iter() {
+        if try_string_to_vk(key).is_none() {
+            problems.push(format!(
+                "mode \"{}\", activation_chord: {}",
+                name,
+                unknown("key", key, KEY_NAMES)
+            ));
+        }
+    }
+    if let Some(Err(err)) = mode.app_match.as_ref().map(AppFilter::new) {
+        problems.push(format!("mode \"{}\", app_match: {}", name, err));
+    }
+    let mut chord: Vec<&String> = mode.activation_chord.iter().collect();
+    chord.sort();
+    chord.dedup();
+    if chord.len() == 1 {
+        problems.push(format!(
+            "mode \"{}\", activation_chord: needs at least two different keys",
+            name
+        ));
+    }
+    if let Some(exit_key) = &mode.exit_key {
+        if try_string_to_vk(exit_key).is_none() {
+            problems.push(format!(
+                "mode \"{}\", exit_key: {}",
+                name,
+                unknown("key", exit_key, KEY_NAMES)
+            ));
+        }
+    }
+    for modifier in mode.auto_modifiers.iter() {
+        if try_string_to_modifier(modifier).is_none() {
+            problems.push(format!(
+                "mode \"{}\", auto_modifiers: {}",
+                name,
+                unknown("modifier", modifier, MODIFIER_NAMES)
+            ));
+        }
+    }
+    if mode.repeat.is_some_and(|repeat| repeat.interval_ms == 0) {
+        problems.push(format!(
+            "mode \"{}\", repeat: interval_ms must be greater than 0",
+            name
+        ));
+    }
+    if !mode.sequences.is_empty() && !mode.key_mapping.is_empty() {
+        problems.push(format!(
+            "mode \"{}\": a leader mode maps sequences, leave key_mapping empty",
+            name
+        ));
+    }
+    let mut sequences: Vec<&String> = mode.sequences.keys().collect();
+    sequences.sort();
+    for sequence in sequences {
+        let mut sequence_problems = Vec::new();
+        if sequence_keys(sequence).next().is_none() {
+            sequence_problems.push("needs at least one key".to_string());
+        }
+        for key in sequence_keys(sequence) {
+            if try_string_to_vk(key).is_none() {
+                sequence_problems.push(unknown("key", key, KEY_NAMES));
+            }
+        }
+        check_entry(&mode.sequences[sequence], &mut sequence_problems);
+        for problem in sequence_problems {
+            problems.push(format!(
+                "mode \"{}\", sequences \"{}\": {}",
+                name, sequence, problem
+            ));
+        }
+    }
+    let mut mapped_keys: Vec<&String> = mode.key_mapping.keys().collect();
+    mapped_keys.sort();
+    for src_key in mapped_keys {
+        let entry = &mode.key_mapping[src_key];
+        let mut mapping_problems = Vec::new();
+        if try_string_to_vk(src_key).is_none() {
+            mapping_problems.push(unknown("key", src_key, KEY_NAMES));
+        }
+        check_entry(entry, &mut mapping_problems);
+        if matches!(entry.repeat, Some(MappingRepeat::Timing(repeat)) if repeat.interval_ms == 0) {
+            mapping_problems.push("repeat interval_ms must be greater than 0".to_string());
+        }
+        for problem in mapping_problems {
+            problems.push(format!(
+                "mode \"{}\", key_mapping \"{}\": {}",
+                name, src_key, problem
+            ));
+        }
+    }
+}
+
+/// The longest `{"delay_ms": N}` step; keys typed meanwhile wait behind it.
+const MAX_DELAY_MS: u64 = 100;
+
+fn check_entry(entry: &KeyMappingEntry, problems: &mut Vec<String>) {
+    let forms = [
+        entry.key.is_some(),
+        entry.text.is_some(),
+        !entry.steps.is_empty(),
+        !entry.tap_dance.is_empty(),
+    ];
+    match forms.iter().filter(|is_set| **is_set).count() {
+        0 => problems.push("needs a key, text, steps or tap_dance".to_string()),
+        1 => {}
+        _ => problems.push("use only one of key, text, steps or tap_dance".to_string()),
+    }
+    check_tap_dance(entry, problems);
+    if let Some(key) = &entry.key {
+        if try_string_to_vk(key).is_none() {
+            problems.push(unknown("key", key, KEY_NAMES));
+        }
+    }
+    for modifier in entry.modifiers.iter() {
+        if try_string_to_modifier(modifier).is_none() {
+            problems.push(unknown("modifier", modifier, MODIFIER_NAMES));
+        }
+    }
+    // Keys pressed by down steps, which must be released again by the end.
+    let mut held: Vec<&str> = Vec::new();
+    for step in entry.steps.iter() {
+        match step {
+            StepConfig::Tap { tap, modifiers } => {
+                if try_string_to_vk(tap).is_none() {
+                    problems.push(unknown("key", tap, KEY_NAMES));
+                }
+                for modifier in modifiers.iter() {
+                    if try_string_to_modifier(modifier).is_none() {
+                        problems.push(unknown("modifier", modifier, MODIFIER_NAMES));
+                    }
+                }
+            }
+            StepConfig::Down { down: key } | StepConfig::Up { up: key } => {
+                if try_string_to_vk(key).is_none() && try_string_to_modifier(key).is_none() {
+                    problems.push(unknown("key", key, KEY_NAMES));
+                }
+                if matches!(step, StepConfig::Down { .. }) {
+                    held.push(key);
+                } else if let Some(pos) = held.iter().position(|k| k == key) {
+                    held.remove(pos);
+                } else {
+                    problems.push(format!("\"{}\" is released without being pressed", key));
+                }
+            }
+            StepConfig::Delay { delay_ms } => {
+                if *delay_ms > MAX_DELAY_MS {
+                    problems.push(format!(
+                        "delay_ms {} is longer than the {} ms a step may wait",
+                        delay_ms, MAX_DELAY_MS
+                    ));
+                }
+            }
+            StepConfig::Text { .. } => {}
+        }
+    }
+    for key in held {
+        problems.push(format!("\"{}\" is pressed but never released", key));
+    }
+}
+
+fn check_tap_dance(entry: &KeyMappingEntry, problems: &mut Vec<String>) {
+    if entry.tap_dance.is_empty() {
+        return;
+    }
+    if matches!(
+        entry.repeat,
+        Some(MappingRepeat::Switch(true) | MappingRepeat::Timing(_))
+    ) {
+        problems.push("a tap_dance key can't repeat".to_string());
+    }
+    let mut seen: Vec<(u32, bool)> = Vec::new();
+    for step in entry.tap_dance.iter() {
+        let name = if step.hold {
+            format!("tap_dance taps {} with hold", step.taps)
+        } else {
+            format!("tap_dance taps {}", step.taps)
+        };
+        if step.taps == 0 {
+            problems.push("tap_dance: taps must be at least 1".to_string());
+        } else if seen.contains(&(step.taps, step.hold)) {
+            problems.push(format!("{}: more than one output", name));
+        }
+        seen.push((step.taps, step.hold));
+        if !step.output.tap_dance.is_empty() {
+            problems.push(format!("{}: can't hold another tap_dance", name));
+            continue;
+        }
+        let mut step_problems = Vec::new();
+        check_entry(&step.output, &mut step_problems);
+        problems.extend(
+            step_problems
+                .into_iter()
+                .map(|problem| format!("{}: {}", name, problem)),
+        );
+    }
+}
+
+/// `unknown key "ESCAPE" (did you mean "ESC"?)`, for a name missing from `names`.
+pub fn unknown(kind: &str, token: &str, names: &'static [(&'static str, u32)]) -> String {
+    let suggestions = suggest_names(token, names);
+    if suggestions.is_empty() {
+        format!("unknown {} \"{}\"", kind, token)
+    } else {
+        let suggestions: Vec<String> = suggestions
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect();
+        format!(
+            "unknown {} \"{}\" (did you mean {}?)",
+            kind,
+            token,
+            suggestions.join(" or ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mode_json;
+
+    fn config(json: &str) -> ModesConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn modes_inherit_the_global_timing() {
+        let modes = build_available_modes(
+            config(
+                r#"{"tap_timeout_ms": 150, "modes": [
+                    {"name": "fast", "activation_keys": ["F"], "auto_modifiers": [], "key_mapping": {}},
+                    {"name": "slow", "activation_keys": ["J"], "auto_modifiers": [], "key_mapping": {},
+                     "tap_timeout_ms": 400}
+                ]}"#,
+            ),
+            MouseMode::from_config(crate::mouse_mode::default_mouse_config()),
+        )
+        .unwrap();
+        let timeouts: Vec<(&str, u128)> = modes
+            .iter()
+            .map(|mode| (mode.get_name(), mode.get_tap_timeout().as_millis()))
+            .collect();
+        assert_eq!(
+            timeouts,
+            vec![("fast", 150), ("slow", 400), ("MouseMode", 150)]
+        );
+    }
+
+    #[test]
+    fn default_modes_are_valid() {
+        assert_eq!(
+            validate_modes_config(&config(&mode_json::get_json_str())),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn unknown_names_are_reported_with_suggestions() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [{
+                "name": "editing",
+                "activation_keys": ["Spacebar"],
+                "auto_modifiers": [],
+                "key_mapping": {
+                    "Q": {"key": "ESCAPE"},
+                    "W": {"key": "C", "modifiers": ["CRTL"]}
+                }
+            }]}"#,
+        ))
+        .unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"mode "editing", activation_keys: unknown key "Spacebar" (did you mean "SPACEBAR"?)"#,
+                r#"mode "editing", key_mapping "Q": unknown key "ESCAPE" (did you mean "ESC"?)"#,
+                r#"mode "editing", key_mapping "W": unknown modifier "CRTL" (did you mean "CTRL"?)"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_are_checked() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [{
+                "name": "macros",
+                "activation_keys": ["M"],
+                "auto_modifiers": [],
+                "key_mapping": {
+                    "A": {"steps": [{"down": "LCTRL"}, {"tap": "K"}, {"up": "LCTRL"}]},
+                    "B": {"steps": [{"down": "CTRL"}, {"tap": "LFET"}]},
+                    "C": {"key": "X", "steps": [{"up": "SHIFT"}]},
+                    "D": {"modifiers": ["CTRL"]}
+                }
+            }]}"#,
+        ))
+        .unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"mode "macros", key_mapping "B": unknown key "LFET" (did you mean "LEFT"?)"#,
+                r#"mode "macros", key_mapping "B": "CTRL" is pressed but never released"#,
+                r#"mode "macros", key_mapping "C": use only one of key, text, steps or tap_dance"#,
+                r#"mode "macros", key_mapping "C": "SHIFT" is released without being pressed"#,
+                r#"mode "macros", key_mapping "D": needs a key, text, steps or tap_dance"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn step_delays_are_capped() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [{
+                "name": "macros",
+                "activation_keys": ["M"],
+                "auto_modifiers": [],
+                "key_mapping": {
+                    "A": {"steps": [{"tap": "K"}, {"delay_ms": 100}, {"tap": "L"}]},
+                    "B": {"steps": [{"tap": "K"}, {"delay_ms": 5000}, {"tap": "L"}]}
+                }
+            }]}"#,
+        ))
+        .unwrap_err();
+        assert_eq!(
+            err,
+            r#"mode "macros", key_mapping "B": delay_ms 5000 is longer than the 100 ms a step may wait"#
+        );
+    }
+
+    #[test]
+    fn tap_dance_steps_are_checked() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [{
+                "name": "symbols",
+                "activation_keys": ["S"],
+                "auto_modifiers": [],
+                "key_mapping": {
+                    "E": {"tap_dance": [
+                        {"taps": 1, "key": "="},
+                        {"taps": 2, "text": "=="},
+                        {"taps": 2, "key": "ESCAPE"},
+                        {"taps": 1, "hold": true}
+                    ]},
+                    "R": {"tap_dance": [{"taps": 0, "key": "R"}], "repeat": {}}
+                }
+            }]}"#,
+        ))
+        .unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"mode "symbols", key_mapping "E": tap_dance taps 2: more than one output"#,
+                r#"mode "symbols", key_mapping "E": tap_dance taps 2: unknown key "ESCAPE" (did you mean "ESC"?)"#,
+                r#"mode "symbols", key_mapping "E": tap_dance taps 1 with hold: needs a key, text, steps or tap_dance"#,
+                r#"mode "symbols", key_mapping "R": a tap_dance key can't repeat"#,
+                r#"mode "symbols", key_mapping "R": tap_dance: taps must be at least 1"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn leader_sequences_are_checked() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [{
+                "name": "leader",
+                "activation_keys": ["CAPSLOCK"],
+                "key_mapping": {"A": {"key": "B"}},
+                "sequences": {
+                    "G S": {"text": "git status"},
+                    "W ESCAPE": {"key": "S", "modifiers": ["CTRL"]},
+                    " ": {"key": "A"}
+                }
+            }]}"#,
+        ))
+        .unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"mode "leader": a leader mode maps sequences, leave key_mapping empty"#,
+                r#"mode "leader", sequences " ": needs at least one key"#,
+                r#"mode "leader", sequences "W ESCAPE": unknown key "ESCAPE" (did you mean "ESC"?)"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn app_matches_are_checked() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [
+                {"name": "ide", "activation_keys": ["D"], "app_match": {"title": "[rs"}},
+                {"name": "games", "activation_keys": ["F"], "app_match": {"exclude": true}}
+            ]}"#,
+        ))
+        .unwrap_err();
+        // The regex error spans several lines of its own.
+        assert!(err.starts_with(r#"mode "ide", app_match: title is not a valid regex"#));
+        assert!(err
+            .ends_with(r#"mode "games", app_match: needs a process_name, window_class or title"#));
+        assert_eq!(
+            err.lines().filter(|line| line.starts_with("mode ")).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn hopeless_names_get_no_suggestion() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [{"name": "m", "activation_keys": ["é"], "auto_modifiers": [], "key_mapping": {}}]}"#,
+        ))
+        .unwrap_err();
+        assert_eq!(err, r#"mode "m", activation_keys: unknown key "é""#);
+    }
+
+    #[test]
+    fn chords_need_two_known_keys() {
+        let err = validate_modes_config(&config(
+            r#"{"modes": [
+                {"name": "one", "activation_chord": ["D", "D"], "auto_modifiers": [], "key_mapping": {}},
+                {"name": "two", "activation_chord": ["D", "FF"], "auto_modifiers": [], "key_mapping": {}}
+            ]}"#,
+        ))
+        .unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"mode "one", activation_chord: needs at least two different keys"#,
+                r#"mode "two", activation_chord: unknown key "FF""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn mouse_exit_key_is_checked() {
+        let mut mouse_config = crate::mouse_mode::default_mouse_config();
+        mouse_config.exit_key = Some("ESCAPE".to_string());
+        let err = build_available_modes(
+            config(&mode_json::get_json_str()),
+            MouseMode::from_config(mouse_config),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err,
+            r#"mouse_config.json, exit_key: unknown key "ESCAPE" (did you mean "ESC"?)"#
+        );
+    }
+}
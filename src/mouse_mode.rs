@@ -1,18 +1,16 @@
 // space_mode.rs
-use crate::conversion::char_to_vk;
-use crate::conversion::{string_to_modifier, string_to_vk};
-use crate::input_simulator;
+use crate::conversion::{char_to_vk, try_string_to_vk};
 use crate::input_simulator::get_char_from_vk_code;
-use crate::key_and_modifiers::KeyAndModifiers;
+use crate::input_sink::{InputSink, MouseButton};
 use crate::key_state::KeyState;
-use crate::mode::Mode;
-use crate::mode_config::ModeConfig;
-use crate::utils::current_time_ms;
+use crate::mode::{Activation, Mode};
+use crate::mode_config::{
+    ActivationStyle, TapHoldStrategy, DEFAULT_BALANCED_MIN_MS, DEFAULT_DOUBLE_TAP_MS,
+    DEFAULT_ONE_SHOT_TIMEOUT_MS, DEFAULT_TAP_TIMEOUT_MS,
+};
 use log::debug;
 use log::info;
-use std::collections::HashMap;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 // use serde for mouse config struct
 use crate::mouse_config_json;
 use serde::{Deserialize, Serialize};
@@ -48,6 +46,24 @@ pub struct MouseConfig {
     dual_wield_multiplier: f64,
     activation_keys: Vec<String>,
     auto_modifiers: Vec<String>,
+    /// Overrides the global tap_timeout_ms from modes.json.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tap_timeout_ms: Option<u64>,
+    #[serde(default)]
+    activation_style: ActivationStyle,
+    /// Leaves the mouse mode while it is locked on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    double_tap_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    one_shot_timeout_ms: Option<u64>,
+    #[serde(default)]
+    tap_hold: TapHoldStrategy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balanced_min_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    require_idle_ms: Option<u64>,
 }
 // define default values for mouse config
 impl Default for MouseConfig {
@@ -88,6 +104,14 @@ impl Default for MouseConfig {
             scroll_max_speed: 1000.0,
             scroll_friction: 0.87,
             auto_modifiers: vec![],
+            tap_timeout_ms: None,
+            activation_style: ActivationStyle::Hold,
+            exit_key: None,
+            double_tap_ms: None,
+            one_shot_timeout_ms: None,
+            tap_hold: TapHoldStrategy::Timeout,
+            balanced_min_ms: None,
+            require_idle_ms: None,
         }
     }
 }
@@ -97,15 +121,20 @@ pub struct MouseMode {
     // ignore
     pub activation_keys: Vec<u32>,
 
-    last_update_millis: u128,
-
-    pub key_mapping: HashMap<u32, KeyAndModifiers>,
+    /// When the last frame was computed; `None` until the first update after activation.
+    last_update: Option<Instant>,
 
     pub key_code_activated_by: Option<u32>,
+    activation: Activation,
+    /// When a tap locked the mode on.
+    locked_at: Option<Instant>,
 
     mouse_vel_x: f64,
 
     mouse_vel_y: f64,
+    /// Sub-pixel movement carried over to the next frame.
+    mouse_remainder_x: f64,
+    mouse_remainder_y: f64,
 
     fast_up_pressed: bool,
     fast_left_pressed: bool,
@@ -121,49 +150,47 @@ pub struct MouseMode {
     scroll_right_pressed: bool,
     scroll_velocity_x: f64,
     scroll_velocity_y: f64,
-    scroll_acceleration: f64,
     scroll_max_speed: f64,
-    scroll_friction: f64,
     auto_modifiers: Vec<u32>,
     was_mode_used: bool,
-    was_repeat: bool,
 }
 
 impl MouseMode {
     pub fn new(config_path: &Path) -> Self {
-        let activation_keys = vec![char_to_vk(' ') as u32];
-        let config = ModeConfig {
-            name: "MouseMode".to_string(),
-            activation_keys: vec![" ".to_string()],
-            key_mapping: HashMap::new(),
-            auto_modifiers: vec![],
-        };
-        let mouse_config = load_mouse_config(&config_path);
-        let key_mapping = config
-            .key_mapping
-            .iter()
-            .map(|(src_key, entry)| {
-                (
-                    string_to_vk(src_key),
-                    KeyAndModifiers {
-                        key: string_to_vk(&entry.key),
-                        modifiers: entry
-                            .modifiers
-                            .iter()
-                            .map(|m| string_to_modifier(m))
-                            .collect(),
-                    },
-                )
-            })
-            .collect();
+        Self::from_config(load_mouse_config(config_path))
+    }
 
+    pub fn from_config(mouse_config: MouseConfig) -> Self {
+        let activation_keys = vec![char_to_vk(' ')];
+        let activation = Activation {
+            style: mouse_config.activation_style,
+            exit_key: mouse_config.exit_key.as_deref().and_then(try_string_to_vk),
+            double_tap: Duration::from_millis(
+                mouse_config.double_tap_ms.unwrap_or(DEFAULT_DOUBLE_TAP_MS),
+            ),
+            one_shot_timeout: Duration::from_millis(
+                mouse_config
+                    .one_shot_timeout_ms
+                    .unwrap_or(DEFAULT_ONE_SHOT_TIMEOUT_MS),
+            ),
+            tap_hold: mouse_config.tap_hold,
+            balanced_min: Duration::from_millis(
+                mouse_config
+                    .balanced_min_ms
+                    .unwrap_or(DEFAULT_BALANCED_MIN_MS),
+            ),
+            require_idle: mouse_config.require_idle_ms.map(Duration::from_millis),
+        };
         Self {
             config: mouse_config,
-            key_mapping,
             activation_keys,
             key_code_activated_by: None,
+            activation,
+            locked_at: None,
             mouse_vel_x: 0.0,
             mouse_vel_y: 0.0,
+            mouse_remainder_x: 0.0,
+            mouse_remainder_y: 0.0,
             fast_up_pressed: false,
             fast_left_pressed: false,
             fast_down_pressed: false,
@@ -172,29 +199,30 @@ impl MouseMode {
             slow_left_pressed: false,
             slow_down_pressed: false,
             slow_right_pressed: false,
-            last_update_millis: current_time_ms(),
+            last_update: None,
             scroll_up_pressed: false,
             scroll_down_pressed: false,
             scroll_left_pressed: false,
             scroll_right_pressed: false,
             scroll_velocity_x: 0.0,
             scroll_velocity_y: 0.0,
-            scroll_acceleration: 100.0,
             scroll_max_speed: 1000.0,
-            scroll_friction: 0.999,
             auto_modifiers: vec![],
             was_mode_used: false,
-            was_repeat: false,
         }
     }
 }
 
+/// The mouse config written on first run.
+pub fn default_mouse_config() -> MouseConfig {
+    serde_json::from_str(&mouse_config_json::get_json_str()).unwrap()
+}
+
 fn load_mouse_config(path: &Path) -> MouseConfig {
-    let mut config: MouseConfig = MouseConfig::default();
+    let config: MouseConfig;
     if !path.exists() {
         println!("mouse config file does not exist, creating it");
-        let config_str = mouse_config_json::get_json_str();
-        config = serde_json::from_str(&config_str).unwrap();
+        config = default_mouse_config();
         fs::write(path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
         println!("successfully wrote to {:?} ", path);
     } else {
@@ -215,7 +243,11 @@ impl Mode for MouseMode {
     fn get_auto_modifiers(&self) -> &Vec<u32> {
         &self.auto_modifiers
     }
-    fn handle_key_down_event<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool {
+    fn handle_key_down_event(
+        &mut self,
+        key_state: &mut KeyState,
+        sink: &mut dyn InputSink,
+    ) -> bool {
         let vk_code = key_state.vk_code as u32;
         // If this key is an activation key and not already activated, record it.
         if self.activation_keys.contains(&vk_code) && self.key_code_activated_by.is_none() {
@@ -287,28 +319,22 @@ impl Mode for MouseMode {
         let middle_click = self.config.middle_click_key;
         match get_char_from_vk_code(key_state.vk_code as u32) {
             c if c == left_click => {
-                input_simulator::simulate_left_down();
+                sink.mouse_button(MouseButton::Left, true);
                 true
             }
             c if c == right_click => {
-                input_simulator::simulate_right_down();
+                sink.mouse_button(MouseButton::Right, true);
                 true
             }
             c if c == middle_click => {
-                input_simulator::simulate_middle_down();
+                sink.mouse_button(MouseButton::Middle, true);
                 true
             }
 
             _ => false,
         }
     }
-    fn was_repeat(&self) -> bool {
-        self.was_repeat
-    }
-    fn set_was_repeat(&mut self, was_repeat: bool) {
-        self.was_repeat = was_repeat.clone();
-    }
-    fn handle_key_up_event<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool {
+    fn handle_key_up_event(&mut self, key_state: &mut KeyState, sink: &mut dyn InputSink) -> bool {
         self.set_was_mode_used(true);
         let fast_up_code = char_to_vk(self.config.fast_up_key);
         let fast_left_code = char_to_vk(self.config.fast_left_key);
@@ -318,9 +344,6 @@ impl Mode for MouseMode {
         let slow_left_code = char_to_vk(self.config.slow_left_key);
         let slow_down_code = char_to_vk(self.config.slow_down_key);
         let slow_right_code = char_to_vk(self.config.slow_right_key);
-        let left_click_code = char_to_vk(self.config.left_click_key);
-        let right_click_code = char_to_vk(self.config.right_click_key);
-        let middle_click_code = char_to_vk(self.config.middle_click_key);
         let scroll_up_code = char_to_vk(self.config.scroll_up_key);
         let scroll_down_code = char_to_vk(self.config.scroll_down_key);
         let scroll_left_code = char_to_vk(self.config.scroll_left_key);
@@ -369,66 +392,56 @@ impl Mode for MouseMode {
         let _middle_click = self.config.middle_click_key;
         match get_char_from_vk_code(key_state.vk_code as u32) {
             c if c == _left_click => {
-                input_simulator::simulate_left_up();
+                sink.mouse_button(MouseButton::Left, false);
                 true
             }
             c if c == _right_click => {
-                input_simulator::simulate_right_up();
+                sink.mouse_button(MouseButton::Right, false);
                 true
             }
             c if c == _middle_click => {
                 println!("🎑🎑🎑middle_click_up");
-                input_simulator::simulate_middle_up();
+                sink.mouse_button(MouseButton::Middle, false);
                 true
             }
             _ => false,
         }
     }
 
-    fn update(&mut self) {
-        let current_millis = current_time_ms();
-        let delta_millis = current_millis.abs_diff(self.last_update_millis);
-        let target_delta_millis = 1000.0 / self.config.fps;
-        // add  clock emoji in the debug message
-        debug!(
-            "🐭>>>>>>>>>>>>>>>>>>>>> delta_millis: {}, target_delta_millis: {}",
-            delta_millis, target_delta_millis
-        );
-        let target_delta_millis = target_delta_millis as u128;
-        debug!(
-            "🐭 target_delta_millis after conversion: {}",
-            target_delta_millis
-        );
-        if delta_millis < target_delta_millis {
-            // add ninja emoji in the debug message
-            debug!("[insert ninja here] Not updating mouse mode because delta_millis {} < target_delta_millis {}\x1b[0m", delta_millis, target_delta_millis);
+    fn update(&mut self, now: Instant, sink: &mut dyn InputSink) {
+        let Some(last_update) = self.last_update else {
+            // The first frame only starts the clock.
+            self.last_update = Some(now);
             return;
-        } else {
+        };
+        let delta = now.saturating_duration_since(last_update);
+        let target_delta = Duration::from_secs_f64(1.0 / self.config.fps);
+        if delta < target_delta {
             debug!(
-                "Just detected that delta_millis {} >= target_delta_millis {}",
-                delta_millis, target_delta_millis
+                "Not updating mouse mode because delta {:?} < target delta {:?}",
+                delta, target_delta
             );
-
-            self.last_update_millis = current_time_ms();
+            return;
         }
+        self.last_update = Some(now);
         // todo: compute motion by our dt
         debug!("🐭 Updating mouse mode");
         // check all 8 directions
-        let dt_seconds = delta_millis as f64 / 1000.0;
+        let dt_seconds = delta.as_secs_f64();
         let mut x_dual_wield_multiplier = 1.0;
         let mut y_dual_wield_multiplier = 1.0;
 
         if self.slow_left_pressed && self.fast_left_pressed {
-            x_dual_wield_multiplier = self.config.dual_wield_multiplier as f64;
+            x_dual_wield_multiplier = self.config.dual_wield_multiplier;
         }
         if self.slow_right_pressed && self.fast_right_pressed {
-            x_dual_wield_multiplier = self.config.dual_wield_multiplier as f64;
+            x_dual_wield_multiplier = self.config.dual_wield_multiplier;
         }
         if self.slow_up_pressed && self.fast_up_pressed {
-            y_dual_wield_multiplier = self.config.dual_wield_multiplier as f64;
+            y_dual_wield_multiplier = self.config.dual_wield_multiplier;
         }
         if self.slow_down_pressed && self.fast_down_pressed {
-            y_dual_wield_multiplier = self.config.dual_wield_multiplier as f64;
+            y_dual_wield_multiplier = self.config.dual_wield_multiplier;
         }
         if self.fast_left_pressed {
             self.mouse_vel_x -= self.config.fast_acceleration * dt_seconds;
@@ -472,9 +485,15 @@ impl Mode for MouseMode {
                 "🐭🐭🐭🐭🐭🐭🐭🐭🐭🐭🐭🐭🐭 Moving mouse by {} {}",
                 self.mouse_vel_x, self.mouse_vel_y
             );
-            let x_move = self.mouse_vel_x * dt_seconds as f64 * x_dual_wield_multiplier;
-            let y_move = self.mouse_vel_y * dt_seconds as f64 * y_dual_wield_multiplier;
-            input_simulator::move_mouse(x_move as i32, y_move as i32);
+            let x_move =
+                self.mouse_vel_x * dt_seconds * x_dual_wield_multiplier + self.mouse_remainder_x;
+            let y_move =
+                self.mouse_vel_y * dt_seconds * y_dual_wield_multiplier + self.mouse_remainder_y;
+            self.mouse_remainder_x = x_move.fract();
+            self.mouse_remainder_y = y_move.fract();
+            if x_move.trunc() != 0.0 || y_move.trunc() != 0.0 {
+                sink.move_mouse(x_move as i32, y_move as i32);
+            }
         }
         if self.scroll_up_pressed {
             self.scroll_velocity_y += self.config.scroll_acceleration * dt_seconds;
@@ -523,14 +542,11 @@ impl Mode for MouseMode {
             self.scroll_velocity_y = 0.0;
         }
         if self.scroll_velocity_x.abs() >= 1.0 || self.scroll_velocity_y.abs() >= 1.0 {
-            input_simulator::simulate_scroll(
+            sink.scroll(
                 self.scroll_velocity_x.round() as i32,
                 self.scroll_velocity_y.round() as i32,
             );
         }
-
-        // todo: use a dt for consistent speed.
-        thread::sleep(Duration::from_millis(50));
     }
 
     fn get_name(&self) -> &str {
@@ -539,7 +555,51 @@ impl Mode for MouseMode {
     fn get_activation_keys(&self) -> &Vec<u32> {
         &self.activation_keys
     }
-    fn check_if_deactivates<'a, 'b>(&'a mut self, key_state: &'b mut KeyState) -> bool {
+    fn get_tap_timeout(&self) -> Duration {
+        Duration::from_millis(self.config.tap_timeout_ms.unwrap_or(DEFAULT_TAP_TIMEOUT_MS))
+    }
+    fn get_activation_chord(&self) -> &[u32] {
+        &[]
+    }
+    fn get_chord_window(&self) -> Duration {
+        Duration::ZERO
+    }
+    fn get_activation(&self) -> &Activation {
+        &self.activation
+    }
+    fn get_locked_at(&self) -> Option<Instant> {
+        self.locked_at
+    }
+    fn set_locked_at(&mut self, locked_at: Option<Instant>) {
+        self.locked_at = locked_at;
+    }
+    fn maps_key(&self, vk_code: u32) -> bool {
+        let config = &self.config;
+        [
+            config.fast_up_key,
+            config.fast_left_key,
+            config.fast_down_key,
+            config.fast_right_key,
+            config.slow_up_key,
+            config.slow_left_key,
+            config.slow_down_key,
+            config.slow_right_key,
+            config.scroll_up_key,
+            config.scroll_down_key,
+            config.scroll_left_key,
+            config.scroll_right_key,
+            config.left_click_key,
+            config.right_click_key,
+            config.middle_click_key,
+        ]
+        .iter()
+        .any(|key| char_to_vk(*key) == vk_code)
+    }
+    fn take_repeated_keys(&mut self) -> Vec<u32> {
+        // Mouse keys act while held; nothing repeats.
+        Vec::new()
+    }
+    fn check_if_deactivates(&mut self, key_state: &mut KeyState) -> bool {
         if let Some(key) = self.key_code_activated_by {
             if key == key_state.vk_code as u32 {
                 info!("MouseMode deactivated by key {:#X}", key_state.vk_code);
@@ -560,3 +620,57 @@ impl Mode for MouseMode {
         self.key_code_activated_by
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_sink::{Action, RecordingSink};
+
+    fn press(mode: &mut MouseMode, key: char, now: Instant) {
+        let mut key_state = KeyState::new(char_to_vk(key) as i32, now);
+        mode.handle_key_down_event(&mut key_state, &mut RecordingSink::new());
+    }
+
+    #[test]
+    fn frames_are_paced_by_the_clock() {
+        let mut mode = MouseMode::from_config(default_mouse_config());
+        let t0 = Instant::now();
+        let mut sink = RecordingSink::new();
+        mode.update(t0, &mut sink);
+        press(&mut mode, 'D', t0);
+        // 10ms is less than a frame at 60 fps
+        mode.update(t0 + Duration::from_millis(10), &mut sink);
+        assert!(sink.actions.is_empty());
+        mode.update(t0 + Duration::from_millis(100), &mut sink);
+        // 4000 px/s² for 0.1 s, less friction, moved for 0.1 s
+        assert_eq!(sink.actions, vec![Action::MouseMove { dx: 34, dy: 0 }]);
+    }
+
+    #[test]
+    fn slow_movement_accumulates_sub_pixel_steps() {
+        let mut mode = MouseMode::from_config(default_mouse_config());
+        let t0 = Instant::now();
+        let mut sink = RecordingSink::new();
+        mode.update(t0, &mut sink);
+        press(&mut mode, 'L', t0);
+        for frame in 1..=30 {
+            mode.update(t0 + Duration::from_millis(17 * frame), &mut sink);
+        }
+        let config = default_mouse_config();
+        let (mut velocity, mut expected) = (0.0, 0.0);
+        for _ in 1..=30 {
+            velocity = (velocity + config.slow_acceleration * 0.017) * config.friction;
+            expected += velocity * 0.017;
+        }
+        let moved: i32 = sink
+            .actions
+            .iter()
+            .map(|action| match action {
+                Action::MouseMove { dx: 0, dy } => *dy,
+                other => panic!("unexpected action {:?}", other),
+            })
+            .sum();
+        // the first frames move less than a pixel each; nothing is lost to truncation
+        assert_eq!(moved, expected as i32);
+    }
+}
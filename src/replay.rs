@@ -0,0 +1,359 @@
+// replay.rs
+//! Headless replay of a recorded key trace.
+//!
+//! A trace is a JSON-lines file with one key event per line:
+//! `{"time_ms": 120, "key": "A", "down": true}`. Each event is fed through the
+//! same engine the keyboard hook uses, and the decision and synthesized output
+//! for every input are printed, so timing reports can be reproduced exactly.
+//! The engine runs on a virtual clock that is stepped a millisecond at a time
+//! between events, so periodic output such as mouse movement is replayed too.
+use crate::clock::ManualClock;
+use crate::conversion::{
+    try_string_to_modifier, try_string_to_vk, vk_to_string, KEY_NAMES, MODIFIER_NAMES,
+};
+use crate::engine::{Decision, Engine, KeyEvent};
+use crate::input_sink::{Action, MouseButton};
+use crate::mode_config::ModesConfig;
+use crate::mode_json;
+use crate::mode_loader;
+use crate::mouse_mode::{self, MouseConfig, MouseMode};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TraceEvent {
+    /// Milliseconds since the start of the trace.
+    pub time_ms: u64,
+    pub key: String,
+    pub down: bool,
+    /// `key` resolved by `parse_trace`.
+    #[serde(skip)]
+    pub vk_code: u32,
+}
+
+/// The outcome of one traced input.
+#[derive(Debug, Clone)]
+pub struct ReplayStep {
+    /// Output of the periodic update since the previous event.
+    pub updates: Vec<Action>,
+    pub event: TraceEvent,
+    pub decision: Decision,
+    /// The active modes after the event, bottom first, joined with " > ".
+    pub mode_after: Option<String>,
+}
+
+pub const USAGE: &str =
+    "usage: bushido_keys_rs replay <trace.jsonl> [--config modes.json] [--mouse-config mouse_config.json]";
+
+/// Entry point for `bushido_keys_rs replay ...`.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut trace_path = None;
+    let mut config_path = None;
+    let mut mouse_config_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--mouse-config" => mouse_config_path = Some(args.next().ok_or(USAGE)?.clone()),
+            _ if trace_path.is_none() => trace_path = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let trace_path = trace_path.ok_or(USAGE)?;
+
+    // Without a config file, replay against the defaults written on first run.
+    let modes_config: ModesConfig = match &config_path {
+        Some(path) => mode_loader::read_json(Path::new(path))?,
+        None => serde_json::from_str(&mode_json::get_json_str())
+            .map_err(|e| format!("modes config: {}", e))?,
+    };
+    let mouse_config: MouseConfig = match &mouse_config_path {
+        Some(path) => mode_loader::read_json(Path::new(path))?,
+        None => mouse_mode::default_mouse_config(),
+    };
+    let trace = fs::read_to_string(&trace_path).map_err(|e| format!("{}: {}", trace_path, e))?;
+    let events = parse_trace(&trace)?;
+
+    let clock = Arc::new(ManualClock::new());
+    let mut engine = Engine::with_clock(
+        mode_loader::build_available_modes(modes_config, MouseMode::from_config(mouse_config))?,
+        clock.clone(),
+    );
+    for step in replay(&mut engine, &clock, &events) {
+        println!("{}", format_step(&step));
+    }
+    Ok(())
+}
+
+/// Parse a JSON-lines trace. Blank lines are skipped.
+/// Keys are named as in the config, or as the replay prints them; time must not go back.
+pub fn parse_trace(trace: &str) -> Result<Vec<TraceEvent>, String> {
+    let mut events: Vec<TraceEvent> = Vec::new();
+    for (index, line) in trace.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let at_line = |e: String| format!("trace line {}: {}", index + 1, e);
+        let mut event: TraceEvent =
+            serde_json::from_str(line).map_err(|e| at_line(e.to_string()))?;
+        event.vk_code = try_string_to_vk(&event.key)
+            .or_else(|| try_string_to_modifier(&event.key))
+            .ok_or_else(|| at_line(mode_loader::unknown("key", &event.key, KEY_NAMES)))?;
+        if let Some(previous) = events.last() {
+            if event.time_ms < previous.time_ms {
+                return Err(at_line(format!(
+                    "time_ms {} is before the previous event's {}",
+                    event.time_ms, previous.time_ms
+                )));
+            }
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Feed every traced event through an engine running on `clock`.
+/// Event times are relative to the clock's start.
+pub fn replay(engine: &mut Engine, clock: &ManualClock, events: &[TraceEvent]) -> Vec<ReplayStep> {
+    events
+        .iter()
+        .map(|event| {
+            // Step the clock like the backends' update thread, which runs every millisecond.
+            let mut updates = Vec::new();
+            let target = Duration::from_millis(event.time_ms);
+            while clock.elapsed() < target {
+                clock.advance(Duration::from_millis(1));
+                updates.extend(engine.update());
+            }
+            let time = engine.now();
+            let decision = engine.process(KeyEvent::new(event.vk_code, event.down, time));
+            ReplayStep {
+                updates,
+                event: event.clone(),
+                decision,
+                mode_after: Some(engine.active_mode_names().join(" > "))
+                    .filter(|names| !names.is_empty()),
+            }
+        })
+        .collect()
+}
+
+/// The config name of a key, so printed keys can be pasted back into a trace.
+fn key_name(vk_code: u32) -> String {
+    KEY_NAMES
+        .iter()
+        .chain(MODIFIER_NAMES.iter())
+        .find(|(_, code)| *code == vk_code)
+        .map(|(name, _)| name.to_string())
+        .or_else(|| vk_to_string(vk_code))
+        .unwrap_or_else(|| format!("{:#X}", vk_code))
+}
+
+pub fn describe_action(action: &Action) -> String {
+    match action {
+        Action::KeyDown(vk_code) => format!("down {}", key_name(*vk_code)),
+        Action::KeyUp(vk_code) => format!("up {}", key_name(*vk_code)),
+        Action::KeyTap { vk_code, modifiers } => {
+            let keys: Vec<String> = modifiers
+                .iter()
+                .chain(std::iter::once(vk_code))
+                .map(|vk| key_name(*vk))
+                .collect();
+            format!("tap {}", keys.join("+"))
+        }
+        Action::MouseMove { dx, dy } => format!("move {},{}", dx, dy),
+        Action::Scroll { dx, dy } => format!("scroll {},{}", dx, dy),
+        Action::MouseButton { button, down } => {
+            let button = match button {
+                MouseButton::Left => "left",
+                MouseButton::Right => "right",
+                MouseButton::Middle => "middle",
+            };
+            format!("{} {}", button, if *down { "down" } else { "up" })
+        }
+        Action::Delay(duration) => format!("wait {}ms", duration.as_millis()),
+        Action::Text(text) => format!("type {:?}", text),
+    }
+}
+
+fn describe_actions(actions: &[Action]) -> String {
+    if actions.is_empty() {
+        "-".to_string()
+    } else {
+        actions
+            .iter()
+            .map(describe_action)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// One line per traced event, preceded by a line for any update output since the last one.
+pub fn format_step(step: &ReplayStep) -> String {
+    let actions = describe_actions(&step.decision.actions);
+    let updates = if step.updates.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "{:>7}ms  {:<27}  {}\n",
+            step.event.time_ms,
+            "(update)",
+            describe_actions(&step.updates)
+        )
+    };
+    format!(
+        "{}{:>7}ms  {:<10} {:<4}  {:<9}  {:<24} [{}]",
+        updates,
+        step.event.time_ms,
+        step.event.key,
+        if step.event.down { "down" } else { "up" },
+        if step.decision.propagate {
+            "propagate"
+        } else {
+            "block"
+        },
+        actions,
+        step.mode_after.as_deref().unwrap_or("no mode")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay_default(events: &[TraceEvent]) -> Vec<ReplayStep> {
+        let modes_config: ModesConfig = serde_json::from_str(&mode_json::get_json_str()).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let mut engine = Engine::with_clock(
+            mode_loader::build_available_modes(
+                modes_config,
+                MouseMode::from_config(mouse_mode::default_mouse_config()),
+            )
+            .unwrap(),
+            clock.clone(),
+        );
+        replay(&mut engine, &clock, events)
+    }
+
+    fn outputs(steps: &[ReplayStep]) -> Vec<String> {
+        steps
+            .iter()
+            .flat_map(|step| step.decision.actions.iter().map(describe_action))
+            .collect()
+    }
+
+    #[test]
+    fn parses_trace_and_reports_bad_lines() {
+        let events = parse_trace(
+            "{\"time_ms\": 0, \"key\": \"A\", \"down\": true}\n\n{\"time_ms\": 5, \"key\": \"A\", \"down\": false}\n",
+        )
+        .unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(!events[1].down);
+        let err = parse_trace("{\"time_ms\": 0}\nnot json").unwrap_err();
+        assert!(err.starts_with("trace line 1"), "{}", err);
+        let err = parse_trace(
+            "{\"time_ms\": 0, \"key\": \"A\", \"down\": true}\n{\"time_ms\": 5, \"key\": \"ESCAPE\", \"down\": true}",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            r#"trace line 2: unknown key "ESCAPE" (did you mean "ESC"?)"#
+        );
+        assert!(parse_trace(r#"{"time_ms": 0, "key": "", "down": true}"#).is_err());
+        let err = parse_trace(
+            "{\"time_ms\": 9, \"key\": \"A\", \"down\": true}\n{\"time_ms\": 5, \"key\": \"A\", \"down\": false}",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "trace line 2: time_ms 5 is before the previous event's 9"
+        );
+    }
+
+    #[test]
+    fn printed_keys_can_be_traced() {
+        for vk_code in [0x1B, 0x25, 0x21, 0x20, 0xA2, 0xBC] {
+            let line = format!(
+                r#"{{"time_ms": 0, "key": "{}", "down": true}}"#,
+                key_name(vk_code)
+            );
+            assert_eq!(parse_trace(&line).unwrap()[0].vk_code, vk_code, "{}", line);
+        }
+    }
+
+    #[test]
+    fn rolling_as_types_both_letters() {
+        // A is released first, quickly and without using num_mode, so it is typed as itself.
+        let trace = r#"
+            {"time_ms": 0, "key": "A", "down": true}
+            {"time_ms": 40, "key": "S", "down": true}
+            {"time_ms": 70, "key": "A", "down": false}
+            {"time_ms": 90, "key": "S", "down": false}
+        "#;
+        let steps = replay_default(&parse_trace(trace).unwrap());
+        assert_eq!(outputs(&steps), vec!["tap A", "tap S"]);
+        assert_eq!(steps[1].mode_after.as_deref(), Some("num_mode"));
+        assert_eq!(steps[3].mode_after, None);
+    }
+
+    #[test]
+    fn nested_as_is_read_as_num_mode() {
+        // S is pressed and released inside the A hold, so it becomes num_mode's 2.
+        let trace = r#"
+            {"time_ms": 0, "key": "A", "down": true}
+            {"time_ms": 40, "key": "S", "down": true}
+            {"time_ms": 60, "key": "S", "down": false}
+            {"time_ms": 90, "key": "A", "down": false}
+        "#;
+        let steps = replay_default(&parse_trace(trace).unwrap());
+        assert_eq!(outputs(&steps), vec!["tap 2"]);
+        assert!(steps[3].decision.propagate);
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let trace = parse_trace(
+            r#"{"time_ms": 0, "key": "K", "down": true}
+               {"time_ms": 300, "key": "D", "down": true}
+               {"time_ms": 350, "key": "D", "down": false}
+               {"time_ms": 400, "key": "K", "down": false}"#,
+        )
+        .unwrap();
+        let first: Vec<String> = replay_default(&trace).iter().map(format_step).collect();
+        let second: Vec<String> = replay_default(&trace).iter().map(format_step).collect();
+        assert_eq!(first, second);
+        assert!(first[2].contains("tap SHIFT+,"), "{}", first[2]);
+    }
+
+    #[test]
+    fn held_mouse_key_moves_the_pointer_between_events() {
+        let trace = r#"
+            {"time_ms": 0, "key": "SPACEBAR", "down": true}
+            {"time_ms": 20, "key": "D", "down": true}
+            {"time_ms": 520, "key": "D", "down": false}
+            {"time_ms": 600, "key": "SPACEBAR", "down": false}
+        "#;
+        let steps = replay_default(&parse_trace(trace).unwrap());
+        assert_eq!(steps[0].mode_after.as_deref(), Some("MouseMode"));
+        let moves: Vec<(i32, i32)> = steps[2]
+            .updates
+            .iter()
+            .map(|action| match action {
+                Action::MouseMove { dx, dy } => (*dx, *dy),
+                other => panic!("unexpected update {:?}", other),
+            })
+            .collect();
+        assert!(moves.len() > 10, "{:?}", moves);
+        assert!(
+            moves.iter().all(|&(dx, dy)| dx > 0 && dy == 0),
+            "{:?}",
+            moves
+        );
+        assert!(format_step(&steps[2]).contains("(update)"));
+        assert_eq!(steps[3].mode_after, None);
+    }
+}
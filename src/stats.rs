@@ -0,0 +1,194 @@
+// stats.rs
+//! Usage statistics: how often each mode is entered, whether its activation key was
+//! tapped or held, which of its mappings are used, how often each key is pressed in
+//! it and how many key events were blocked.
+//!
+//! The engine counts as it goes. A background thread takes the counts every minute
+//! and adds them to `usage_stats.json` in the config directory, so the file keeps
+//! growing across runs.
+use crate::conversion::{modifer_to_string_or_none, vk_to_string};
+use crate::engine::Engine;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeStats {
+    #[serde(default)]
+    pub activations: u64,
+    /// Releases of the activation key that typed the key or locked the mode.
+    #[serde(default)]
+    pub taps: u64,
+    /// Releases of the activation key after the mode was held.
+    #[serde(default)]
+    pub holds: u64,
+    /// Uses of each mapping, by its key or sequence in `modes.json`.
+    #[serde(default)]
+    pub mappings: BTreeMap<String, u64>,
+    /// Presses of each key while the mode was the one handling it.
+    #[serde(default)]
+    pub keys: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageStats {
+    #[serde(default)]
+    pub key_down_events: u64,
+    /// Key events kept from the system, to be replaced by the modes' output.
+    #[serde(default)]
+    pub blocked_events: u64,
+    /// Presses of each key while no mode was active.
+    #[serde(default)]
+    pub keys: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub modes: BTreeMap<String, ModeStats>,
+}
+
+impl UsageStats {
+    pub fn mode(&mut self, name: &str) -> &mut ModeStats {
+        self.modes.entry(name.to_string()).or_default()
+    }
+
+    /// Count one use of each of `mappings` in mode `name`.
+    pub fn count_mappings(&mut self, name: &str, mappings: Vec<String>) {
+        let mode = self.mode(name);
+        for mapping in mappings {
+            *mode.mappings.entry(mapping).or_default() += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Add the counts of `other` to these.
+    pub fn merge(&mut self, other: &UsageStats) {
+        self.key_down_events += other.key_down_events;
+        self.blocked_events += other.blocked_events;
+        add_counts(&mut self.keys, &other.keys);
+        for (name, other) in other.modes.iter() {
+            let mode = self.mode(name);
+            mode.activations += other.activations;
+            mode.taps += other.taps;
+            mode.holds += other.holds;
+            add_counts(&mut mode.mappings, &other.mappings);
+            add_counts(&mut mode.keys, &other.keys);
+        }
+    }
+}
+
+fn add_counts(counts: &mut BTreeMap<String, u64>, other: &BTreeMap<String, u64>) {
+    for (name, count) in other.iter() {
+        *counts.entry(name.clone()).or_default() += count;
+    }
+}
+
+/// The name a key's presses are counted under; left and right modifiers are told apart.
+pub fn key_name(vk_code: u32) -> String {
+    modifer_to_string_or_none(vk_code)
+        .or_else(|| vk_to_string(vk_code))
+        .unwrap_or_else(|| format!("{:#04X}", vk_code))
+}
+
+/// Add `stats` to the totals in `path`, creating the file if needed.
+/// The file is replaced in one go, so a crash can't leave it half written. A file that
+/// can't be parsed anyway is moved to `<path>.bad` and the totals start over.
+pub fn flush(path: &Path, stats: &UsageStats) -> Result<(), String> {
+    let with_path = |path: &Path, e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+    let mut totals = if path.exists() {
+        let json = fs::read_to_string(path).map_err(|e| with_path(path, &e))?;
+        match serde_json::from_str(&json) {
+            Ok(totals) => totals,
+            Err(e) => {
+                let bad = path.with_extension("json.bad");
+                error!("{}, moving it to {:?}", with_path(path, &e), bad);
+                fs::rename(path, &bad).map_err(|e| with_path(&bad, &e))?;
+                UsageStats::default()
+            }
+        }
+    } else {
+        UsageStats::default()
+    };
+    totals.merge(stats);
+    let json = serde_json::to_string_pretty(&totals).map_err(|e| with_path(path, &e))?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json).map_err(|e| with_path(&tmp, &e))?;
+    fs::rename(&tmp, path).map_err(|e| with_path(path, &e))
+}
+
+/// Periodically move the engine's counts into `usage_stats.json` in the config directory.
+/// Counts that can't be written are kept for the next attempt.
+pub fn spawn(config_dir: &Path, engine: &'static Mutex<Engine>) {
+    let path = config_dir.join("usage_stats.json");
+    thread::spawn(move || {
+        let mut pending = UsageStats::default();
+        loop {
+            thread::sleep(FLUSH_INTERVAL);
+            pending.merge(&engine.lock().unwrap().take_stats());
+            if pending.is_empty() {
+                continue;
+            }
+            match flush(&path, &pending) {
+                Ok(()) => {
+                    info!("Wrote usage statistics to {:?}", path);
+                    pending = UsageStats::default();
+                }
+                Err(e) => error!("Failed to write usage statistics: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mode_loader;
+
+    #[test]
+    fn flushing_adds_to_the_totals_on_disk() {
+        let path = std::env::temp_dir().join(format!(
+            "bushido_keys_usage_stats_{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let mut stats = UsageStats {
+            key_down_events: 3,
+            blocked_events: 2,
+            ..Default::default()
+        };
+        let mode = stats.mode("num_mode");
+        mode.activations = 1;
+        mode.holds = 1;
+        mode.mappings.insert("S".to_string(), 2);
+        mode.keys.insert("S".to_string(), 2);
+        stats.keys.insert("A".to_string(), 1);
+
+        flush(&path, &stats).unwrap();
+        flush(&path, &stats).unwrap();
+        let totals: UsageStats = mode_loader::read_json(&path).unwrap();
+        assert_eq!(totals.key_down_events, 6);
+        assert_eq!(totals.blocked_events, 4);
+        assert_eq!(totals.modes["num_mode"].holds, 2);
+        assert_eq!(totals.modes["num_mode"].mappings["S"], 4);
+        assert_eq!(totals.modes["num_mode"].keys["S"], 4);
+        assert_eq!(totals.keys["A"], 2);
+
+        // A file cut short is set aside rather than failing every flush from now on.
+        fs::write(&path, "{\"key_down_").unwrap();
+        flush(&path, &stats).unwrap();
+        let bad = path.with_extension("json.bad");
+        assert_eq!(fs::read_to_string(&bad).unwrap(), "{\"key_down_");
+        let totals: UsageStats = mode_loader::read_json(&path).unwrap();
+        assert_eq!(totals, stats);
+        assert!(!path.with_extension("json.tmp").exists());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&bad).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+// windows_hook.rs
+//! The Windows backend: a low-level keyboard hook feeding the engine and
+//! SendInput carrying out its decisions.
+use std::mem::MaybeUninit;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use log::info;
+use windows::Win32::Foundation::{HINSTANCE, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::UI::WindowsAndMessaging::{
+    CallNextHookEx, DispatchMessageW, GetMessageW, SetWindowsHookExW, TranslateMessage,
+    UnhookWindowsHookEx, HHOOK, KBDLLHOOKSTRUCT, LLKHF_INJECTED, MSG, WH_KEYBOARD_LL, WM_KEYDOWN,
+    WM_KEYUP, WM_SYSKEYDOWN, WM_SYSKEYUP,
+};
+
+use crate::control;
+use crate::engine::KeyEvent;
+use crate::foreground_window::ForegroundWindow;
+use crate::input_simulator::SendInputSink;
+use crate::input_sink::{Action, InputSink};
+use crate::ENGINE;
+
+/// Batches with a delay in them, sent in order by a thread of their own: a hook
+/// that doesn't return in time is skipped by Windows.
+static QUEUE: OnceLock<Sender<Vec<Action>>> = OnceLock::new();
+/// Batches in the queue; while there are any, later batches line up behind them.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// Send a batch right away, or queue it when it has to wait.
+fn send_batch(actions: Vec<Action>) {
+    if actions.is_empty() {
+        return;
+    }
+    let delayed = actions.iter().any(|a| matches!(a, Action::Delay(_)));
+    if delayed || QUEUED.load(Ordering::SeqCst) > 0 {
+        if let Some(queue) = QUEUE.get() {
+            QUEUED.fetch_add(1, Ordering::SeqCst);
+            // The sender thread lives as long as the program.
+            let _ = queue.send(actions);
+            return;
+        }
+    }
+    SendInputSink.send_batch(&actions);
+}
+
+/// The low-level keyboard hook procedure.
+/// - It translates the hook data into a KeyEvent for the engine.
+/// - It synthesizes the engine's actions and blocks or forwards the original event.
+extern "system" fn keyboard_proc(n_code: i32, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
+    let message = w_param.0 as u32;
+    let is_key_down = message == WM_KEYDOWN || message == WM_SYSKEYDOWN;
+    let is_key_up = message == WM_KEYUP || message == WM_SYSKEYUP;
+    if !is_key_down && !is_key_up {
+        return unsafe { CallNextHookEx(None, n_code, w_param, l_param) };
+    }
+    // Extract kb_data once safely
+    let kb_data = unsafe { *(l_param.0 as *const KBDLLHOOKSTRUCT) };
+    // Our own SendInput events come back through the hook; let them through untouched.
+    if (kb_data.flags.0 & LLKHF_INJECTED.0) != 0 {
+        return unsafe { CallNextHookEx(None, n_code, w_param, l_param) };
+    }
+    let mut engine = ENGINE.lock().unwrap();
+    let event = KeyEvent::new(kb_data.vkCode, is_key_down, engine.now());
+    let decision = engine.process(event);
+    drop(engine);
+    send_batch(decision.actions);
+    if decision.propagate {
+        return unsafe { CallNextHookEx(None, n_code, w_param, l_param) };
+    }
+    LRESULT(1)
+}
+
+/// Install the keyboard hook and pump messages until the program exits.
+/// `config_dir` is where the control channel finds the config files.
+pub fn run(config_dir: &Path) {
+    ENGINE
+        .lock()
+        .unwrap()
+        .set_context_provider(Arc::new(ForegroundWindow));
+    let (queue, queued) = mpsc::channel::<Vec<Action>>();
+    let _ = QUEUE.set(queue);
+    thread::spawn(move || {
+        for actions in queued {
+            SendInputSink.send_batch(&actions);
+            QUEUED.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+    // Answer `bushido_keys_rs ctl` and other local clients.
+    control::spawn(config_dir, &ENGINE, |actions| send_batch(actions.to_vec()));
+    // Update active mode thread
+    thread::spawn(|| loop {
+        let actions = ENGINE.lock().unwrap().update();
+        send_batch(actions);
+        thread::sleep(Duration::from_millis(1));
+    });
+
+    let hook_handle: HHOOK = unsafe {
+        let h_instance: HINSTANCE = GetModuleHandleW(None)
+            .expect("Failed to get module handle")
+            .into();
+        SetWindowsHookExW(WH_KEYBOARD_LL, Some(keyboard_proc), Some(h_instance), 0)
+            .expect("Failed to install keyboard hook")
+    };
+    if hook_handle.is_invalid() {
+        eprintln!("Failed to install keyboard hook.");
+        return;
+    }
+
+    info!("Rust version running... Press ESC to exit.");
+
+    // Windows message loop.
+    unsafe {
+        let mut msg = MaybeUninit::<MSG>::uninit();
+        while GetMessageW(msg.as_mut_ptr(), None, 0, 0).into() {
+            let _ = TranslateMessage(msg.as_ptr());
+            DispatchMessageW(msg.as_ptr());
+        }
+        let _ = UnhookWindowsHookEx(hook_handle);
+    }
+}